@@ -0,0 +1,250 @@
+use crate::layout::LayoutVariant;
+
+/// Physical size of the keyboard
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FormFactor {
+    /// Full size, with numpad
+    FullSize,
+    /// Tenkeyless, without numpad
+    Tenkeyless,
+    /// Not a keyboard at all
+    Mouse,
+}
+
+/// Type of key lighting
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backlight {
+    /// No backlight (NBL)
+    None,
+    /// Single color backlight (BL)
+    Single,
+    /// Per key RGB lighting
+    Rgb,
+}
+
+/// Features verified to work on a model
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Capabilities {
+    pub animation: bool,
+    pub custom_colors: bool,
+    pub key_remap: bool,
+}
+
+/// Known Cherry device, see COMPATIBILITY.md
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DeviceModel {
+    pub product_id: u16,
+    pub name: &'static str,
+    pub form_factor: FormFactor,
+    pub backlight: Backlight,
+    pub layout_variant: LayoutVariant,
+    /// Product id the device enumerates with in service / bootloader mode
+    pub bootloader_product_id: Option<u16>,
+    /// Whether anyone reported the model to work
+    pub tested: bool,
+    pub capabilities: Capabilities,
+}
+
+const UNTESTED: Capabilities = Capabilities {
+    animation: false,
+    custom_colors: false,
+    key_remap: false,
+};
+
+const COLORS_VERIFIED: Capabilities = Capabilities {
+    animation: true,
+    custom_colors: true,
+    key_remap: false,
+};
+
+/// Shorthand to keep the table below readable
+const fn model(
+    product_id: u16,
+    name: &'static str,
+    form_factor: FormFactor,
+    backlight: Backlight,
+) -> DeviceModel {
+    DeviceModel {
+        product_id,
+        name,
+        form_factor,
+        backlight,
+        layout_variant: LayoutVariant::Ansi,
+        bootloader_product_id: None,
+        tested: false,
+        capabilities: UNTESTED,
+    }
+}
+
+impl DeviceModel {
+    /// Mark model as tested with the given verified features
+    const fn tested(self, capabilities: Capabilities) -> Self {
+        Self {
+            tested: true,
+            capabilities,
+            ..self
+        }
+    }
+
+    /// Set product id of the service / bootloader mode
+    const fn bootloader(self, product_id: u16) -> Self {
+        Self {
+            bootloader_product_id: Some(product_id),
+            ..self
+        }
+    }
+
+    /// Mark model as having an ISO layout
+    const fn iso(self) -> Self {
+        Self {
+            layout_variant: LayoutVariant::Iso,
+            ..self
+        }
+    }
+
+    /// Look up model by its product id
+    pub fn from_product_id(product_id: u16) -> Option<&'static DeviceModel> {
+        DEVICE_MODELS
+            .iter()
+            .find(|model| model.product_id == product_id)
+    }
+
+    /// Look up model by the product id of its service / bootloader mode
+    pub fn from_bootloader_product_id(product_id: u16) -> Option<&'static DeviceModel> {
+        DEVICE_MODELS
+            .iter()
+            .find(|model| model.bootloader_product_id == Some(product_id))
+    }
+
+    /// Whether key colors can be set, i.e. the model has RGB lighting
+    pub fn supports_color(&self) -> bool {
+        self.backlight == Backlight::Rgb
+    }
+}
+
+/// Attached device as found by `find_devices`
+///
+/// Bus number and address identify the device until it is unplugged,
+/// the port path stays the same across replugging into the same port.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeviceDescriptor {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus_number: u8,
+    pub address: u8,
+    /// Ports from the root hub down to the device
+    pub port_numbers: Vec<u8>,
+    /// `None` if the device has no serial number or could not be opened to read it
+    pub serial_number: Option<String>,
+    pub model: Option<&'static DeviceModel>,
+}
+
+impl DeviceDescriptor {
+    /// Port path in sysfs notation, e.g. "1-4.2"
+    pub fn port_path(&self) -> String {
+        let ports: Vec<String> = self.port_numbers.iter().map(u8::to_string).collect();
+
+        format!("{}-{}", self.bus_number, ports.join("."))
+    }
+
+    /// Model name, or "Unknown model" if the product id is not in the registry
+    pub fn model_name(&self) -> &'static str {
+        self.model.map_or("Unknown model", |model| model.name)
+    }
+}
+
+impl std::fmt::Display for DeviceDescriptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Bus {:03} Device {:03} Port {} ID {:04x}:{:04x} {}",
+            self.bus_number,
+            self.address,
+            self.port_path(),
+            self.vendor_id,
+            self.product_id,
+            self.model_name()
+        )?;
+        if let Some(serial_number) = &self.serial_number {
+            write!(f, " (serial: {})", serial_number)?;
+        }
+
+        Ok(())
+    }
+}
+
+use Backlight::{None as Nbl, Rgb, Single as Bl};
+use FormFactor::{FullSize as Fl, Mouse, Tenkeyless as Tkl};
+
+/// Registry of known devices, sorted by product id
+pub static DEVICE_MODELS: &[DeviceModel] = &[
+    model(0x0077, "MX BOARD 3.0S FL NBL", Fl, Nbl),
+    model(0x0079, "MX BOARD 3.0S FL RGB", Fl, Rgb).tested(COLORS_VERIFIED),
+    model(0x0083, "MX BOARD 3.0S FL RGB KOREAN", Fl, Rgb),
+    model(0x00AB, "MX 1.0 FL BL", Fl, Bl),
+    model(0x00AC, "MX BOARD 1.0 TKL RGB", Tkl, Rgb),
+    model(0x00B7, "MX BOARD 8.0 TKL RGB", Tkl, Rgb),
+    model(0x00BB, "MX BOARD 10.0 FL RGB", Fl, Rgb),
+    model(0x00C3, "G80 3000 TKL NBL", Tkl, Nbl),
+    model(0x00C4, "MX BOARD 2.0S FL RGB", Fl, Rgb),
+    model(0x00C5, "G80 3000 TKL RGB", Tkl, Rgb).tested(COLORS_VERIFIED),
+    model(0x00C7, "MV BOARD 3.0 FL RGB", Fl, Rgb),
+    model(0x00C9, "CCF MX 8.0 TKL BL", Tkl, Bl),
+    model(0x00CA, "CCF MX 1.0 TKL BL", Tkl, Bl),
+    model(0x00CB, "CCF MX 1.0 TKL NBL", Tkl, Nbl),
+    model(0x00CD, "G80 3000 TKL NBL KOREAN", Tkl, Nbl),
+    model(0x00CE, "MX BOARD 2.0S FL NBL", Fl, Nbl),
+    model(0x00D2, "MX 1.0 FL NBL", Fl, Nbl),
+    model(0x00D3, "MX 1.0 FL RGB", Fl, Rgb),
+    model(0x00DD, "G80 3000N TKL RGB", Tkl, Rgb)
+        .bootloader(0x00E0)
+        .tested(COLORS_VERIFIED),
+    model(0x00DE, "G80 3000N FL RGB", Fl, Rgb).bootloader(0x00E1),
+    model(0x00DF, "MX BOARD 10.0N FL RGB", Fl, Rgb)
+        .bootloader(0x00E2)
+        .tested(COLORS_VERIFIED),
+    model(0x01A4, "MC 2.1 (Mouse)", Mouse, Nbl),
+    model(0x01A6, "MX BOARD 2.0S FL RGB DE", Fl, Rgb).iso(),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_lookup() {
+        assert!(DEVICE_MODELS
+            .windows(2)
+            .all(|pair| pair[0].product_id < pair[1].product_id));
+
+        let g80 = DeviceModel::from_product_id(0x00dd).unwrap();
+        assert_eq!(g80.name, "G80 3000N TKL RGB");
+        assert_eq!(g80.form_factor, FormFactor::Tenkeyless);
+        assert!(g80.tested && g80.supports_color());
+        assert_eq!(DeviceModel::from_bootloader_product_id(0x00e0), Some(g80));
+
+        assert!(!DeviceModel::from_product_id(0x00c3)
+            .unwrap()
+            .supports_color());
+        assert_eq!(DeviceModel::from_product_id(0x00e0), None);
+    }
+
+    #[test]
+    fn descriptor_display() {
+        let descriptor = DeviceDescriptor {
+            vendor_id: 0x046a,
+            product_id: 0x00dd,
+            bus_number: 1,
+            address: 7,
+            port_numbers: vec![4, 2],
+            serial_number: None,
+            model: DeviceModel::from_product_id(0x00dd),
+        };
+
+        assert_eq!(descriptor.port_path(), "1-4.2");
+        assert_eq!(
+            descriptor.to_string(),
+            "Bus 001 Device 007 Port 1-4.2 ID 046a:00dd G80 3000N TKL RGB"
+        );
+    }
+}
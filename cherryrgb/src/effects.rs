@@ -0,0 +1,394 @@
+use crate::{
+    heatmap::{ColorRamp, KeyPressCounts},
+    layout::{GRID_COLUMNS, GRID_ROWS},
+    models::{CustomKeyLeds, TOTAL_KEYS},
+};
+use rgb::RGB8;
+use std::{f32::consts::PI, time::Duration};
+
+/// Software effect, rendering one frame of key colors at a time
+///
+/// Keys are placed on the key index grid, see `GRID_COLUMNS`.
+/// Run it with `CherryKeyboard::play_effect`.
+pub trait Effect {
+    /// Render the frame `elapsed` after the effect was started
+    fn render(&mut self, elapsed: Duration) -> CustomKeyLeds;
+}
+
+/// Effect reacting to key presses
+///
+/// Run it with `CherryKeyboard::play_reactive_effect`.
+pub trait ReactiveEffect: Effect {
+    /// Key with LED index `key_index` was pressed `elapsed` after the effect was started
+    fn key_pressed(&mut self, key_index: usize, elapsed: Duration);
+}
+
+/// Position of a key index within the grid, both coordinates within 0.0..=1.0
+fn key_position(key_index: usize) -> (f32, f32) {
+    let column = key_index % GRID_COLUMNS;
+    let row = key_index / GRID_COLUMNS;
+
+    (
+        column as f32 / (GRID_COLUMNS - 1) as f32,
+        row as f32 / (GRID_ROWS - 1) as f32,
+    )
+}
+
+/// Column and row of a key index within the grid
+fn grid_position(key_index: usize) -> (f32, f32) {
+    (
+        (key_index % GRID_COLUMNS) as f32,
+        (key_index / GRID_COLUMNS) as f32,
+    )
+}
+
+/// Render a frame by calling `color` with the index and position of every key
+fn render_keys<F: Fn(usize, (f32, f32)) -> RGB8>(color: F) -> CustomKeyLeds {
+    let colors: Vec<RGB8> = (0..TOTAL_KEYS)
+        .map(|key_index| color(key_index, key_position(key_index)))
+        .collect();
+
+    CustomKeyLeds::from_leds(colors).expect("Frame has exactly TOTAL_KEYS keys")
+}
+
+/// Progress within the current period, 0.0..1.0
+fn phase(elapsed: Duration, period: Duration) -> f32 {
+    if period.is_zero() {
+        return 0.0;
+    }
+
+    (elapsed.as_secs_f32() / period.as_secs_f32()).fract()
+}
+
+/// Linear interpolation between two colors, `t` within 0.0..=1.0
+pub(crate) fn lerp(from: RGB8, to: RGB8, t: f32) -> RGB8 {
+    let channel = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+
+    RGB8::new(
+        channel(from.r, to.r),
+        channel(from.g, to.g),
+        channel(from.b, to.b),
+    )
+}
+
+/// Convert hue (0.0..1.0, wrapping) at full saturation and value to RGB
+fn hue_to_rgb(hue: f32) -> RGB8 {
+    let h = hue.rem_euclid(1.0) * 6.0;
+    let x = 1.0 - (h % 2.0 - 1.0).abs();
+    let (r, g, b) = match h as u8 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+
+    RGB8::new(
+        (r * 255.0).round() as u8,
+        (g * 255.0).round() as u8,
+        (b * 255.0).round() as u8,
+    )
+}
+
+/// Two color gradient moving from left to right
+pub struct GradientSweep {
+    pub from: RGB8,
+    pub to: RGB8,
+    /// Time the gradient takes to move across the keyboard once
+    pub period: Duration,
+}
+
+impl Effect for GradientSweep {
+    fn render(&mut self, elapsed: Duration) -> CustomKeyLeds {
+        let offset = phase(elapsed, self.period);
+
+        render_keys(|_, (x, _)| {
+            // Triangle wave, so the gradient wraps around without a hard edge
+            let t = 1.0 - ((x - offset).rem_euclid(1.0) * 2.0 - 1.0).abs();
+            lerp(self.from, self.to, t)
+        })
+    }
+}
+
+/// Classic plasma effect cycling through all hues
+pub struct Plasma {
+    /// Time the pattern takes to cycle through once
+    pub period: Duration,
+}
+
+impl Effect for Plasma {
+    fn render(&mut self, elapsed: Duration) -> CustomKeyLeds {
+        let t = phase(elapsed, self.period) * 2.0 * PI;
+
+        render_keys(|_, (x, y)| {
+            let (x, y) = (x * 2.0 * PI, y * PI);
+            let value = (x + t).sin()
+                + (y * 2.0 - t).sin()
+                + ((x + y + t).sin() + (x * x + y * y).sqrt().sin()) / 2.0;
+            // value is within -3.0..=3.0
+            hue_to_rgb(value / 6.0 + 0.5 + t / (2.0 * PI))
+        })
+    }
+}
+
+/// Every key fades in and out with its own phase offset
+pub struct KeyBreathing {
+    pub color: RGB8,
+    /// Time of one full fade in and out
+    pub period: Duration,
+}
+
+impl Effect for KeyBreathing {
+    fn render(&mut self, elapsed: Duration) -> CustomKeyLeds {
+        let t = phase(elapsed, self.period);
+
+        render_keys(|key_index, _| {
+            // Spread phases with the golden ratio, so neighbours differ
+            let offset = (key_index as f32 * 0.618_034).fract();
+            let brightness = (1.0 - ((t + offset) * 2.0 * PI).cos()) / 2.0;
+            lerp(RGB8::new(0, 0, 0), self.color, brightness)
+        })
+    }
+}
+
+/// Pressed keys light up and fade out
+pub struct FadingTrail {
+    pub color: RGB8,
+    /// Time a key takes to fade out
+    pub fade: Duration,
+    last_pressed: Vec<Option<Duration>>,
+}
+
+impl FadingTrail {
+    pub fn new(color: RGB8, fade: Duration) -> Self {
+        Self {
+            color,
+            fade,
+            last_pressed: vec![None; TOTAL_KEYS],
+        }
+    }
+}
+
+impl Effect for FadingTrail {
+    fn render(&mut self, elapsed: Duration) -> CustomKeyLeds {
+        render_keys(|key_index, _| match self.last_pressed[key_index] {
+            Some(pressed) => {
+                let age = elapsed.saturating_sub(pressed).as_secs_f32();
+                let brightness = 1.0 - (age / self.fade.as_secs_f32()).min(1.0);
+                lerp(RGB8::new(0, 0, 0), self.color, brightness)
+            }
+            None => RGB8::new(0, 0, 0),
+        })
+    }
+}
+
+impl ReactiveEffect for FadingTrail {
+    fn key_pressed(&mut self, key_index: usize, elapsed: Duration) {
+        if let Some(pressed) = self.last_pressed.get_mut(key_index) {
+            *pressed = Some(elapsed);
+        }
+    }
+}
+
+/// Rings spreading from pressed keys across the keyboard
+pub struct Ripple {
+    pub color: RGB8,
+    /// Keys the ring travels per second
+    pub speed: f32,
+    /// Time until a ring has faded out
+    pub fade: Duration,
+    /// Origin key index and press time of the active rings
+    ripples: Vec<(usize, Duration)>,
+}
+
+impl Ripple {
+    pub fn new(color: RGB8, speed: f32, fade: Duration) -> Self {
+        Self {
+            color,
+            speed,
+            fade,
+            ripples: Vec::new(),
+        }
+    }
+}
+
+impl Effect for Ripple {
+    fn render(&mut self, elapsed: Duration) -> CustomKeyLeds {
+        let fade = self.fade;
+        self.ripples
+            .retain(|(_, pressed)| elapsed.saturating_sub(*pressed) < fade);
+
+        render_keys(|key_index, _| {
+            let (column, row) = grid_position(key_index);
+            let intensity = self
+                .ripples
+                .iter()
+                .map(|&(origin, pressed)| {
+                    let (origin_column, origin_row) = grid_position(origin);
+                    let distance = (column - origin_column).hypot(row - origin_row);
+                    let age = elapsed.saturating_sub(pressed).as_secs_f32();
+                    let radius = age * self.speed;
+                    // Ring is about one key wide, fading out over its lifetime
+                    let ring = (1.0 - (distance - radius).abs()).max(0.0);
+                    ring * (1.0 - age / fade.as_secs_f32())
+                })
+                .fold(0.0f32, f32::max);
+
+            lerp(RGB8::new(0, 0, 0), self.color, intensity)
+        })
+    }
+}
+
+impl ReactiveEffect for Ripple {
+    fn key_pressed(&mut self, key_index: usize, elapsed: Duration) {
+        self.ripples.push((key_index, elapsed));
+    }
+}
+
+/// Keys colored by how often they were pressed, see `KeyPressCounts::render`
+#[derive(Default)]
+pub struct TypingHeatmap {
+    counts: KeyPressCounts,
+    ramp: ColorRamp,
+}
+
+impl TypingHeatmap {
+    /// Start without any presses, graded with the default color ramp
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Continue from previously recorded presses, e.g. loaded with `KeyPressCounts::load`
+    pub fn from_counts(counts: KeyPressCounts, ramp: ColorRamp) -> Self {
+        Self { counts, ramp }
+    }
+
+    /// Presses recorded so far
+    pub fn key_press_counts(&self) -> &KeyPressCounts {
+        &self.counts
+    }
+}
+
+impl Effect for TypingHeatmap {
+    fn render(&mut self, _elapsed: Duration) -> CustomKeyLeds {
+        self.counts.render(&self.ramp)
+    }
+}
+
+impl ReactiveEffect for TypingHeatmap {
+    fn key_pressed(&mut self, key_index: usize, _elapsed: Duration) {
+        // Out of range indices are simply not counted
+        let _ = self.counts.add(key_index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::OwnRGB8;
+
+    fn led(frame: &CustomKeyLeds, key_index: usize) -> OwnRGB8 {
+        frame.get_led(key_index).unwrap().clone()
+    }
+
+    #[test]
+    fn gradient_sweep() {
+        let mut effect = GradientSweep {
+            from: RGB8::new(0, 0, 0),
+            to: RGB8::new(0xff, 0, 0),
+            period: Duration::from_secs(2),
+        };
+
+        let frame = effect.render(Duration::ZERO);
+        assert_eq!(led(&frame, 0), RGB8::new(0, 0, 0).into());
+        assert_eq!(led(&frame, 10), RGB8::new(0xff, 0, 0).into());
+        // Same column in another row
+        assert_eq!(led(&frame, 10 + GRID_COLUMNS), led(&frame, 10));
+
+        // Half a period later the gradient moved by half the width
+        let frame = effect.render(Duration::from_secs(1));
+        assert_eq!(led(&frame, 10), RGB8::new(0, 0, 0).into());
+    }
+
+    #[test]
+    fn key_breathing() {
+        let color = RGB8::new(0x40, 0x80, 0xff);
+        let mut effect = KeyBreathing {
+            color,
+            period: Duration::from_secs(4),
+        };
+
+        // Key 0 has no offset: off at the start, full color after half a period
+        assert_eq!(
+            led(&effect.render(Duration::ZERO), 0),
+            RGB8::new(0, 0, 0).into()
+        );
+        let frame = effect.render(Duration::from_secs(2));
+        assert_eq!(led(&frame, 0), color.into());
+        assert_ne!(led(&frame, 1), led(&frame, 0));
+    }
+
+    #[test]
+    fn fading_trail() {
+        let color = RGB8::new(0, 0xff, 0);
+        let mut effect = FadingTrail::new(color, Duration::from_secs(1));
+
+        effect.key_pressed(5, Duration::from_secs(1));
+        assert_eq!(led(&effect.render(Duration::from_secs(1)), 5), color.into());
+        assert_eq!(
+            led(&effect.render(Duration::from_millis(1500)), 5),
+            RGB8::new(0, 0x80, 0).into()
+        );
+        assert_eq!(
+            led(&effect.render(Duration::from_secs(3)), 5),
+            RGB8::new(0, 0, 0).into()
+        );
+        assert_eq!(
+            led(&effect.render(Duration::from_secs(1)), 6),
+            RGB8::new(0, 0, 0).into()
+        );
+    }
+
+    #[test]
+    fn ripple() {
+        let color = RGB8::new(0xff, 0xff, 0xff);
+        let mut effect = Ripple::new(color, 2.0, Duration::from_secs(10));
+        let origin = 2 * GRID_COLUMNS + 10;
+
+        effect.key_pressed(origin, Duration::ZERO);
+        // After one second the ring is two keys away from its origin
+        let frame = effect.render(Duration::from_secs(1));
+        assert_eq!(led(&frame, origin), RGB8::new(0, 0, 0).into());
+        assert_ne!(led(&frame, origin + 2), RGB8::new(0, 0, 0).into());
+        assert_eq!(led(&frame, origin + 2), led(&frame, origin - 2));
+
+        // Faded rings are dropped
+        effect.render(Duration::from_secs(10));
+        assert!(effect.ripples.is_empty());
+    }
+
+    #[test]
+    fn typing_heatmap() {
+        let mut effect = TypingHeatmap::new();
+        for _ in 0..4 {
+            effect.key_pressed(1, Duration::ZERO);
+        }
+        effect.key_pressed(2, Duration::ZERO);
+        effect.key_pressed(TOTAL_KEYS, Duration::ZERO);
+
+        let frame = effect.render(Duration::ZERO);
+        assert_eq!(led(&frame, 0), RGB8::new(0, 0, 0).into());
+        assert_eq!(led(&frame, 1), RGB8::new(0xff, 0, 0).into());
+        assert_ne!(led(&frame, 2), led(&frame, 1));
+        assert_eq!(effect.key_press_counts().counts()[1], 4);
+    }
+
+    #[test]
+    fn hues() {
+        assert_eq!(hue_to_rgb(0.0), RGB8::new(0xff, 0, 0));
+        assert_eq!(hue_to_rgb(1.0 / 3.0), RGB8::new(0, 0xff, 0));
+        assert_eq!(hue_to_rgb(2.0 / 3.0), RGB8::new(0, 0, 0xff));
+        assert_eq!(hue_to_rgb(1.0), hue_to_rgb(0.0));
+    }
+}
@@ -0,0 +1,148 @@
+use thiserror::Error;
+
+/// Result type of the cherryrgb library
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors returned by the cherryrgb library
+#[derive(Debug, Error)]
+pub enum Error {
+    /// No (supported) Cherry keyboard is attached
+    #[error("No matching devices found")]
+    NoDeviceFound,
+
+    /// Insufficient permissions to access the device, see udev rules in README
+    #[error("Permission denied while accessing the keyboard")]
+    PermissionDenied,
+
+    /// Device does not expose the expected USB configuration / interfaces
+    #[error(
+        "Unsupported device layout: {configurations} configuration(s) and {interfaces} interface(s), expected 1 and 2"
+    )]
+    UnsupportedLayout { configurations: u8, interfaces: u8 },
+
+    /// The keyboard model lacks the hardware for a command
+    #[error("{model} does not support {feature}")]
+    UnsupportedFeature {
+        model: &'static str,
+        feature: &'static str,
+    },
+
+    /// libusb was built without hotplug support (e.g. on Windows)
+    #[error("Hotplug is not supported on this platform")]
+    HotplugUnsupported,
+
+    /// The keyboard did not answer in time
+    #[error("Timeout while communicating with the keyboard")]
+    Timeout,
+
+    /// Packet checksum does not match its content
+    #[error("Invalid checksum, expected: {expected}, got: {got}")]
+    ChecksumMismatch { expected: u16, got: u16 },
+
+    /// Keyboard answered with a different payload type, i.e. rejected the command
+    #[error("Unexpected response, expected payload type {expected:#04x}, got {got:#04x}")]
+    UnexpectedResponse { expected: u8, got: u8 },
+
+    /// Response does not start with the magic byte 0x04, e.g. a stray report
+    #[error("Unexpected response, expected magic byte 0x04, got {got:#04x}")]
+    BadMagic { got: u8 },
+
+    /// Command is experimental and was not enabled, see `CherryKeyboard::with_experimental`
+    #[error(
+        "{0} is experimental and not verified against hardware, it has to be enabled explicitly"
+    )]
+    Experimental(&'static str),
+
+    /// Key name is not part of the layout, see `Layout`
+    #[error("Unknown key '{0}'")]
+    UnknownKey(String),
+
+    /// Layout name is not one of the known layouts
+    #[error("Unknown layout '{0}', expected full-size-ansi, full-size-iso, tkl-ansi or tkl-iso")]
+    UnknownLayout(String),
+
+    /// Key index exceeds the number of keys
+    #[error("Key index {0} out of bounds")]
+    KeyIndexOutOfBounds(usize),
+
+    /// More key colors supplied than the keyboard has keys
+    #[error("Invalid number of key leds: {0}")]
+    InvalidKeyCount(usize),
+
+    /// Color profile is valid JSON, but its content is not
+    #[error("Invalid color profile: {0}")]
+    InvalidProfile(String),
+
+    /// Macro definition is invalid
+    #[error("Invalid macro: {0}")]
+    InvalidMacro(String),
+
+    /// Heatmap file or color ramp is invalid
+    #[error("Invalid heatmap: {0}")]
+    InvalidHeatmap(String),
+
+    /// Daemon received a message it does not understand
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    /// Daemon failed to execute a request
+    #[error("Daemon error: {0}")]
+    Daemon(String),
+
+    /// Color profile is not valid JSON
+    #[error("Failed to parse color profile")]
+    ProfileJson(#[from] serde_json::Error),
+
+    /// Any other libusb error
+    #[error("USB error")]
+    Usb(#[source] rusb::Error),
+
+    /// Packet (de)serialization failed
+    #[error("Packet serialization error")]
+    Binrw(#[from] binrw::Error),
+
+    /// Any other I/O error, e.g. from the hidraw transport
+    #[error("I/O error")]
+    Io(#[source] std::io::Error),
+}
+
+impl Error {
+    /// True for errors that may go away when the command is repeated
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::Timeout
+                | Error::ChecksumMismatch { .. }
+                | Error::UnexpectedResponse { .. }
+                | Error::BadMagic { .. }
+        )
+    }
+}
+
+impl From<rusb::Error> for Error {
+    fn from(err: rusb::Error) -> Self {
+        match err {
+            rusb::Error::Timeout => Error::Timeout,
+            rusb::Error::Access => Error::PermissionDenied,
+            rusb::Error::NoDevice => Error::NoDeviceFound,
+            _ => Error::Usb(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        // hidraw reports an unplugged keyboard as ENODEV
+        #[cfg(target_os = "linux")]
+        if err.raw_os_error() == Some(libc::ENODEV) {
+            return Error::NoDeviceFound;
+        }
+
+        match err.kind() {
+            std::io::ErrorKind::TimedOut => Error::Timeout,
+            std::io::ErrorKind::PermissionDenied => Error::PermissionDenied,
+            std::io::ErrorKind::NotFound => Error::NoDeviceFound,
+            _ => Error::Io(err),
+        }
+    }
+}
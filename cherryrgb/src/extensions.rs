@@ -32,6 +32,11 @@ impl OwnRGB8 {
     pub fn new(r: u8, g: u8, b: u8) -> Self {
         Self(RGB8 { r, g, b })
     }
+
+    /// Format as hex string, e.g. "ff00ff"
+    pub fn to_hex(&self) -> String {
+        hex::encode([self.0.r, self.0.g, self.0.b])
+    }
 }
 
 impl From<RGB8> for OwnRGB8 {
@@ -40,6 +45,12 @@ impl From<RGB8> for OwnRGB8 {
     }
 }
 
+impl From<OwnRGB8> for RGB8 {
+    fn from(val: OwnRGB8) -> Self {
+        val.0
+    }
+}
+
 impl BinRead for OwnRGB8 {
     type Args = ();
 
@@ -80,7 +91,7 @@ impl FromStr for OwnRGB8 {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let val = match s.len() {
             6 => {
-                let bytes = hex::decode(s).unwrap();
+                let bytes = hex::decode(s).map_err(|_| "Invalid hex string")?;
                 RGB8 {
                     r: bytes[0],
                     g: bytes[1],
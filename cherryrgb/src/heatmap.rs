@@ -0,0 +1,285 @@
+use crate::{
+    effects::lerp,
+    extensions::OwnRGB8,
+    input::{KeyEvent, KeyEventReader},
+    layout::Layout,
+    models::{CustomKeyLeds, TOTAL_KEYS},
+    Error, Result,
+};
+use rgb::RGB8;
+use serde_json::{Map, Value};
+use std::{fs, io::Read, path::Path, str::FromStr};
+
+/// Colors a heatmap is graded with, from the least to the most pressed key
+///
+/// Parses from comma separated hex colors, e.g. "0000ff,ffff00,ff0000".
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorRamp {
+    stops: Vec<RGB8>,
+}
+
+impl ColorRamp {
+    /// Ramp through the given colors, at least two are required
+    pub fn new(stops: Vec<RGB8>) -> Result<Self> {
+        if stops.len() < 2 {
+            return Err(Error::InvalidHeatmap(
+                "Color ramp needs at least two colors".to_string(),
+            ));
+        }
+
+        Ok(Self { stops })
+    }
+
+    /// Color at `t` within 0.0..=1.0, evenly spread over the stops
+    pub fn color_at(&self, t: f32) -> RGB8 {
+        let position = t.clamp(0.0, 1.0) * (self.stops.len() - 1) as f32;
+        let index = (position as usize).min(self.stops.len() - 2);
+
+        lerp(
+            self.stops[index],
+            self.stops[index + 1],
+            position - index as f32,
+        )
+    }
+}
+
+impl Default for ColorRamp {
+    /// Blue, green, yellow, red
+    fn default() -> Self {
+        Self {
+            stops: vec![
+                RGB8::new(0, 0, 0xff),
+                RGB8::new(0, 0xff, 0),
+                RGB8::new(0xff, 0xff, 0),
+                RGB8::new(0xff, 0, 0),
+            ],
+        }
+    }
+}
+
+impl FromStr for ColorRamp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let stops = s
+            .split(',')
+            .map(|hex| {
+                OwnRGB8::from_str(hex.trim())
+                    .map(RGB8::from)
+                    .map_err(|e| Error::InvalidHeatmap(format!("parsing hex color '{hex}': {e}")))
+            })
+            .collect::<Result<_>>()?;
+
+        Self::new(stops)
+    }
+}
+
+/// Number of presses per key, as recorded for a typing heatmap
+///
+/// Stored as JSON in the same shape as color profiles: `{ "<key index>": <presses>, ... }`
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyPressCounts {
+    counts: Vec<u64>,
+    /// Layout keycodes are mapped in, see `with_layout`
+    layout: Layout,
+}
+
+impl Default for KeyPressCounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyPressCounts {
+    /// Initialize with zero presses for all keys
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; TOTAL_KEYS],
+            layout: Layout::default(),
+        }
+    }
+
+    /// Map recorded keycodes in `layout` instead of the default layout
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Presses per key index
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Count a press of the key at provided index
+    pub fn add(&mut self, key_index: usize) -> Result<()> {
+        let count = self
+            .counts
+            .get_mut(key_index)
+            .ok_or(Error::KeyIndexOutOfBounds(key_index))?;
+        *count += 1;
+
+        Ok(())
+    }
+
+    /// Count `event` if it is a press of a known key, returns whether it was counted
+    pub fn record(&mut self, event: &KeyEvent) -> bool {
+        match self.layout.key_index_from_keycode(event.keycode) {
+            Some(key_index) if event.pressed => self.add(key_index).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Count all presses of a recorded event log, returns the number of presses counted
+    pub fn record_all<R: Read>(&mut self, events: KeyEventReader<R>) -> Result<u64> {
+        let mut recorded = 0;
+        for event in events {
+            if self.record(&event?) {
+                recorded += 1;
+            }
+        }
+
+        Ok(recorded)
+    }
+
+    /// Parse heatmap file content
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: Value =
+            serde_json::from_str(json).map_err(|e| Error::InvalidHeatmap(e.to_string()))?;
+        let root = value
+            .as_object()
+            .ok_or_else(|| Error::InvalidHeatmap("Expected a JSON object".to_string()))?;
+
+        let mut result = Self::new();
+        for (key, count) in root {
+            let key_index: usize = key
+                .parse()
+                .map_err(|e| Error::InvalidHeatmap(format!("parsing key index {key}: {e}")))?;
+            let count = count.as_u64().ok_or_else(|| {
+                Error::InvalidHeatmap(format!("Invalid count for key with index {key_index}"))
+            })?;
+
+            *result
+                .counts
+                .get_mut(key_index)
+                .ok_or(Error::KeyIndexOutOfBounds(key_index))? = count;
+        }
+
+        Ok(result)
+    }
+
+    /// Encode as heatmap file content, keys never pressed are omitted
+    pub fn to_json(&self) -> String {
+        let root: Map<String, Value> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(index, count)| (index.to_string(), (*count).into()))
+            .collect();
+
+        Value::Object(root).to_string()
+    }
+
+    /// Load heatmap file, starting with no presses if it does not exist yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    /// Save heatmap file, replacing it atomically so an interrupted save keeps the old counts
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+
+        fs::write(&temp, self.to_json())?;
+        fs::rename(&temp, path)?;
+
+        Ok(())
+    }
+
+    /// Grade keys by their number of presses, relative to the most pressed key
+    ///
+    /// Keys never pressed stay off.
+    pub fn render(&self, ramp: &ColorRamp) -> CustomKeyLeds {
+        let max = self.counts.iter().copied().max().unwrap_or(0);
+        let colors: Vec<RGB8> = self
+            .counts
+            .iter()
+            .map(|&count| match count {
+                0 => RGB8::new(0, 0, 0),
+                _ => ramp.color_at(count as f32 / max as f32),
+            })
+            .collect();
+
+        CustomKeyLeds::from_leds(colors).expect("Heatmap has exactly TOTAL_KEYS keys")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_ramp() {
+        let ramp: ColorRamp = "000000, 0000ff,ff0000".parse().unwrap();
+
+        assert_eq!(ramp.color_at(0.0), RGB8::new(0, 0, 0));
+        assert_eq!(ramp.color_at(0.25), RGB8::new(0, 0, 0x80));
+        assert_eq!(ramp.color_at(0.5), RGB8::new(0, 0, 0xff));
+        assert_eq!(ramp.color_at(1.0), RGB8::new(0xff, 0, 0));
+        assert_eq!(ramp.color_at(2.0), RGB8::new(0xff, 0, 0));
+
+        assert!("ff0000".parse::<ColorRamp>().is_err());
+        assert!("ff0000,nope".parse::<ColorRamp>().is_err());
+    }
+
+    #[test]
+    fn key_press_counts() {
+        let mut counts = KeyPressCounts::new();
+        for _ in 0..4 {
+            counts.add(10).unwrap();
+        }
+        counts.add(20).unwrap();
+        assert!(counts.add(TOTAL_KEYS).is_err());
+
+        // Release and unknown keycode are not counted
+        assert!(!counts.record(&KeyEvent {
+            keycode: 30,
+            pressed: false
+        }));
+        assert!(!counts.record(&KeyEvent {
+            keycode: 240,
+            pressed: true
+        }));
+
+        let json = counts.to_json();
+        assert_eq!(json, r#"{"10":4,"20":1}"#);
+        assert_eq!(KeyPressCounts::from_json(&json).unwrap(), counts);
+        assert!(KeyPressCounts::from_json(r#"{"500":1}"#).is_err());
+        assert!(KeyPressCounts::from_json(r#"{"1":"many"}"#).is_err());
+        assert!(matches!(
+            KeyPressCounts::from_json("{"),
+            Err(Error::InvalidHeatmap(_))
+        ));
+
+        // Numpad enter is only counted on full size keyboards
+        let numpad_enter = KeyEvent {
+            keycode: 96,
+            pressed: true,
+        };
+        let mut tkl = KeyPressCounts::new().with_layout(Layout::TENKEYLESS_ANSI);
+        assert!(!tkl.record(&numpad_enter));
+        assert!(KeyPressCounts::new().record(&numpad_enter));
+
+        let ramp = ColorRamp::default();
+        let frame = counts.render(&ramp);
+        assert_eq!(frame.get_led(0), Some(&RGB8::new(0, 0, 0).into()));
+        assert_eq!(frame.get_led(10), Some(&RGB8::new(0xff, 0, 0).into()));
+        assert_eq!(frame.get_led(20), Some(&ramp.color_at(0.25).into()));
+    }
+}
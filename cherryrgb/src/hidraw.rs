@@ -0,0 +1,231 @@
+use crate::{
+    devices::DeviceDescriptor,
+    models::UsbIdentity,
+    transport::{Report, Transport, INTERFACE_NUM, REPORT_SIZE},
+    Error, Result,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+const SYSFS_HIDRAW: &str = "/sys/class/hidraw";
+
+/// Parse `HID_ID=<bus>:<vendor>:<product>` line of a hid device uevent
+/// and return (vendor_id, product_id)
+fn parse_hid_id(uevent: &str) -> Option<(u16, u16)> {
+    let hid_id = uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("HID_ID="))?;
+    let mut parts = hid_id.split(':').skip(1);
+    let vendor_id = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let product_id = u32::from_str_radix(parts.next()?, 16).ok()?;
+
+    Some((vendor_id as u16, product_id as u16))
+}
+
+/// Read USB interface number of the hid device behind a hidraw node
+fn interface_number(hid_device: &Path) -> Option<u8> {
+    // hidrawN/device points at the hid device, its parent is the usb interface
+    let interface = fs::canonicalize(hid_device).ok()?.parent()?.to_owned();
+    let number = fs::read_to_string(interface.join("bInterfaceNumber")).ok()?;
+
+    u8::from_str_radix(number.trim(), 16).ok()
+}
+
+/// Sysfs path of the hid device behind a hidraw node, e.g. `/dev/hidraw3`
+fn hid_device_path(node: &Path) -> Result<PathBuf> {
+    let name = node.file_name().ok_or(Error::NoDeviceFound)?;
+
+    Ok(Path::new(SYSFS_HIDRAW).join(name).join("device"))
+}
+
+/// Resolve the sysfs directory of the USB device a hid device belongs to
+fn usb_device_dir(hid_device: &Path) -> Result<PathBuf> {
+    // hid device -> usb interface -> usb device
+    Ok(fs::canonicalize(hid_device)?
+        .ancestors()
+        .nth(2)
+        .ok_or(Error::NoDeviceFound)?
+        .to_owned())
+}
+
+/// Read a decimal sysfs attribute, e.g. `busnum`
+fn read_number(dir: &Path, name: &str) -> Option<u8> {
+    fs::read_to_string(dir.join(name)).ok()?.trim().parse().ok()
+}
+
+/// Read identity of the USB device a hid device belongs to from sysfs
+fn usb_identity(hid_device: &Path) -> Result<UsbIdentity> {
+    let usb_device = usb_device_dir(hid_device)?;
+    let attribute = |name: &str| {
+        fs::read_to_string(usb_device.join(name))
+            .ok()
+            .map(|value| value.trim().to_owned())
+    };
+    let hex_id = |name: &str| {
+        attribute(name)
+            .and_then(|id| u16::from_str_radix(&id, 16).ok())
+            .unwrap_or_default()
+    };
+
+    Ok(UsbIdentity {
+        vendor_id: hex_id("idVendor"),
+        product_id: hex_id("idProduct"),
+        // bcdDevice is exported as plain hex digits, e.g. "0102"
+        device_version: attribute("bcdDevice").and_then(|bcd| {
+            let (major, minor) = bcd.split_at(bcd.len().checked_sub(2)?);
+            Some(format!("{}.{}", major.trim_start_matches('0'), minor))
+        }),
+        manufacturer: attribute("manufacturer"),
+        product: attribute("product"),
+        serial_number: attribute("serial"),
+    })
+}
+
+/// Find the hidraw nodes (e.g. `/dev/hidraw3`) of the keyboard's vendor interface
+pub fn find_hidraw_nodes(vendor_id: u16, product_id: u16) -> Result<Vec<PathBuf>> {
+    let mut nodes: Vec<PathBuf> = fs::read_dir(SYSFS_HIDRAW)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            let hid_device = entry.path().join("device");
+            let ids = fs::read_to_string(hid_device.join("uevent"))
+                .ok()
+                .and_then(|uevent| parse_hid_id(&uevent));
+
+            ids == Some((vendor_id, product_id))
+                && interface_number(&hid_device) == Some(INTERFACE_NUM)
+        })
+        .map(|entry| Path::new("/dev").join(entry.file_name()))
+        .collect();
+
+    if nodes.is_empty() {
+        return Err(Error::NoDeviceFound);
+    }
+
+    nodes.sort();
+    Ok(nodes)
+}
+
+/// Transport using the Linux hidraw interface
+///
+/// In contrast to `UsbTransport` the kernel HID driver stays attached,
+/// so the keyboard keeps working normally while the LEDs are driven.
+pub struct HidrawTransport {
+    file: File,
+    path: PathBuf,
+}
+
+impl HidrawTransport {
+    /// Discover the hidraw node of the given keyboard and open it
+    pub fn new(vendor_id: u16, product_id: u16) -> Result<Self> {
+        let nodes = find_hidraw_nodes(vendor_id, product_id)?;
+
+        Self::open(&nodes[0])
+    }
+
+    /// Open the hidraw node of the exact USB device described by `descriptor`
+    pub fn from_descriptor(descriptor: &DeviceDescriptor) -> Result<Self> {
+        let nodes = find_hidraw_nodes(descriptor.vendor_id, descriptor.product_id)?;
+        let node = nodes
+            .iter()
+            .find(|node| {
+                let usb_device = hid_device_path(node).and_then(|device| usb_device_dir(&device));
+                usb_device.is_ok_and(|dir| {
+                    read_number(&dir, "busnum") == Some(descriptor.bus_number)
+                        && read_number(&dir, "devnum") == Some(descriptor.address)
+                })
+            })
+            .ok_or(Error::NoDeviceFound)?;
+
+        Self::open(node)
+    }
+
+    /// Open a specific hidraw node, e.g. `/dev/hidraw3`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        log::debug!("* Connected to: {}", path.display());
+
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl HidrawTransport {
+    /// Wait until the node is ready for `events` (`POLLIN` / `POLLOUT`), at most `timeout`
+    fn poll(&self, events: libc::c_short, timeout: Duration) -> Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+        match ready {
+            0 => Err(Error::Timeout),
+            n if n < 0 => Err(std::io::Error::last_os_error().into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Transport for HidrawTransport {
+    /// Writes output report, the first byte (0x04) doubles as report id
+    fn send_report(&self, report: &Report, timeout: Duration) -> Result<()> {
+        self.poll(libc::POLLOUT, timeout)?;
+        (&self.file).write_all(report)?;
+
+        Ok(())
+    }
+
+    /// Reads the next input report with id 0x04, waiting at most `timeout`
+    ///
+    /// The node also delivers other reports of the interface (e.g. media keys),
+    /// those are skipped.
+    fn recv_report(&self, timeout: Duration) -> Result<Report> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            self.poll(libc::POLLIN, remaining)?;
+
+            let mut response = [0u8; REPORT_SIZE];
+            let len = (&self.file).read(&mut response)?;
+            if len == REPORT_SIZE && response[0] == 0x04 {
+                return Ok(response);
+            }
+
+            log::trace!(
+                "hidraw skipping report with id {:#04x} and {} bytes",
+                response[0],
+                len
+            );
+        }
+    }
+
+    /// Read identity from sysfs, the hidraw node itself does not expose it
+    fn identity(&self) -> Result<UsbIdentity> {
+        usb_identity(&hid_device_path(&self.path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hid_id_from_uevent() {
+        let uevent = "DRIVER=hid-generic\n\
+                      HID_ID=0003:0000046A:000000DD\n\
+                      HID_NAME=Cherry GmbH CHERRY Keyboard\n";
+        assert_eq!(parse_hid_id(uevent), Some((0x046a, 0x00dd)));
+        assert_eq!(parse_hid_id("DRIVER=hid-generic\n"), None);
+        assert_eq!(parse_hid_id("HID_ID=0003:zzzz:00DD\n"), None);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{
+    describe_device, devices::DeviceDescriptor, is_supported, Error, Result, CHERRY_USB_VID,
+};
+use rusb::{Hotplug, HotplugBuilder, UsbContext};
+use std::{sync::mpsc, time::Duration};
+
+/// Supported Cherry device being attached or detached
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HotplugEvent {
+    Arrived(DeviceDescriptor),
+    Left(DeviceDescriptor),
+}
+
+/// Forwards libusb hotplug callbacks to the watcher
+struct EventSender {
+    sender: mpsc::Sender<HotplugEvent>,
+}
+
+impl EventSender {
+    fn describe(device: &rusb::Device<rusb::Context>) -> Option<DeviceDescriptor> {
+        let desc = device.device_descriptor().ok()?;
+
+        is_supported(desc.product_id()).then(|| describe_device(device, &desc))
+    }
+
+    fn send(&self, event: HotplugEvent) {
+        // Receiver is only gone while the watcher is being dropped
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Hotplug<rusb::Context> for EventSender {
+    fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+        if let Some(descriptor) = Self::describe(&device) {
+            self.send(HotplugEvent::Arrived(descriptor));
+        }
+    }
+
+    fn device_left(&mut self, device: rusb::Device<rusb::Context>) {
+        if let Some(descriptor) = Self::describe(&device) {
+            self.send(HotplugEvent::Left(descriptor));
+        }
+    }
+}
+
+/// Watches for Cherry keyboards being attached or detached
+///
+/// Keyboards already attached when the watcher is created are reported as
+/// arrived first. Keyboards which re-enumerate on resume from suspend show up
+/// as left and arrived again.
+///
+/// Descriptors carry no serial number, as devices must not be opened from
+/// within libusb callbacks.
+pub struct HotplugWatcher {
+    context: rusb::Context,
+    receiver: mpsc::Receiver<HotplugEvent>,
+    _registration: rusb::Registration<rusb::Context>,
+}
+
+impl HotplugWatcher {
+    /// Start watching, optionally only for keyboards with the given product id
+    pub fn new(product_id: Option<u16>) -> Result<Self> {
+        if !rusb::has_hotplug() {
+            return Err(Error::HotplugUnsupported);
+        }
+
+        let context = rusb::Context::new()?;
+        let (sender, receiver) = mpsc::channel();
+
+        let mut builder = HotplugBuilder::new();
+        builder.vendor_id(CHERRY_USB_VID).enumerate(true);
+        if let Some(product_id) = product_id {
+            builder.product_id(product_id);
+        }
+        let registration = builder.register(&context, Box::new(EventSender { sender }))?;
+
+        Ok(Self {
+            context,
+            receiver,
+            _registration: registration,
+        })
+    }
+
+    /// Wait at most `timeout` for the next event, `None` waits until libusb has any event
+    ///
+    /// Returns `Ok(None)` if libusb handled events unrelated to Cherry keyboards.
+    pub fn next_event(&self, timeout: Option<Duration>) -> Result<Option<HotplugEvent>> {
+        if let Ok(event) = self.receiver.try_recv() {
+            return Ok(Some(event));
+        }
+
+        self.context.handle_events(timeout)?;
+
+        Ok(self.receiver.try_recv().ok())
+    }
+}
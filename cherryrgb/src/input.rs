@@ -0,0 +1,208 @@
+use crate::{layout::Layout, Result};
+#[cfg(target_os = "linux")]
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use std::{
+    io::{ErrorKind, Read},
+    mem::size_of,
+    sync::mpsc,
+    thread,
+};
+
+#[cfg(target_os = "linux")]
+const SYSFS_INPUT: &str = "/sys/class/input";
+
+/// `struct timeval` consists of two longs, which are pointer sized on Linux
+const TIMEVAL_SIZE: usize = 2 * size_of::<usize>();
+/// Size of `struct input_event`: timeval, type (u16), code (u16), value (i32)
+pub const INPUT_EVENT_SIZE: usize = TIMEVAL_SIZE + 8;
+
+const EV_KEY: u16 = 0x01;
+/// Event value of a key held down long enough to repeat
+const KEY_REPEAT: i32 = 2;
+
+/// Key index of the LED belonging to a Linux keycode, e.g. 30 (`KEY_A`)
+///
+/// Assumes the default layout, use `Layout::key_index_from_keycode` for a particular model.
+pub fn keycode_to_key_index(keycode: u16) -> Option<usize> {
+    Layout::default().key_index_from_keycode(keycode)
+}
+
+/// Key being pressed or released, as reported by evdev
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeyEvent {
+    /// Linux keycode, e.g. 30 (`KEY_A`)
+    pub keycode: u16,
+    pub pressed: bool,
+}
+
+impl KeyEvent {
+    /// Key index of the LED belonging to the key, if known
+    pub fn key_index(&self) -> Option<usize> {
+        keycode_to_key_index(self.keycode)
+    }
+}
+
+/// Reads key events from an evdev node (e.g. `/dev/input/event3`) or a recording of one
+///
+/// Other event types and key repeats are skipped.
+pub struct KeyEventReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> KeyEventReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    /// Read the next key event, `None` at end of input
+    fn read_event(&mut self) -> Result<Option<KeyEvent>> {
+        let mut buf = [0u8; INPUT_EVENT_SIZE];
+
+        loop {
+            match self.reader.read_exact(&mut buf) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+
+            let event = &buf[TIMEVAL_SIZE..];
+            let event_type = u16::from_ne_bytes([event[0], event[1]]);
+            let keycode = u16::from_ne_bytes([event[2], event[3]]);
+            let value = i32::from_ne_bytes([event[4], event[5], event[6], event[7]]);
+
+            if event_type == EV_KEY && value != KEY_REPEAT {
+                return Ok(Some(KeyEvent {
+                    keycode,
+                    pressed: value != 0,
+                }));
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for KeyEventReader<R> {
+    type Item = Result<KeyEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_event().transpose()
+    }
+}
+
+/// Read each source on its own thread and forward the key events through one channel
+///
+/// A thread stops at the end of its input, on a read error or once the receiver is
+/// dropped. Threads blocked in a read, e.g. on an evdev node, are left behind until
+/// the next event arrives.
+pub fn forward_key_events<R: Read + Send + 'static>(
+    sources: Vec<KeyEventReader<R>>,
+) -> mpsc::Receiver<KeyEvent> {
+    let (sender, receiver) = mpsc::channel();
+
+    for source in sources {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            for event in source {
+                match event {
+                    Ok(event) => {
+                        if sender.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("Failed to read key events: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    receiver
+}
+
+/// Find the evdev nodes (e.g. `/dev/input/event3`) of the keyboard which report keys
+#[cfg(target_os = "linux")]
+pub fn find_input_nodes(vendor_id: u16, product_id: u16) -> Result<Vec<PathBuf>> {
+    let attribute = |dir: &Path, name: &str| {
+        fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|value| value.trim().to_owned())
+    };
+
+    let mut nodes: Vec<PathBuf> = fs::read_dir(SYSFS_INPUT)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+        .filter(|entry| {
+            // eventN/device is the input device
+            let input_device = entry.path().join("device");
+            let hex_id = |name| {
+                attribute(&input_device, name).and_then(|id| u16::from_str_radix(&id, 16).ok())
+            };
+            let reports_keys = attribute(&input_device, "capabilities/ev")
+                .and_then(|ev| u32::from_str_radix(&ev, 16).ok())
+                .is_some_and(|ev| ev & (1 << EV_KEY) != 0);
+
+            hex_id("id/vendor") == Some(vendor_id)
+                && hex_id("id/product") == Some(product_id)
+                && reports_keys
+        })
+        .map(|entry| Path::new("/dev/input").join(entry.file_name()))
+        .collect();
+
+    if nodes.is_empty() {
+        return Err(crate::Error::NoDeviceFound);
+    }
+
+    nodes.sort();
+    Ok(nodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Encode a `struct input_event` as the kernel would
+    fn input_event(event_type: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut event = vec![0u8; TIMEVAL_SIZE];
+        event.extend(event_type.to_ne_bytes());
+        event.extend(code.to_ne_bytes());
+        event.extend(value.to_ne_bytes());
+        event
+    }
+
+    #[test]
+    fn read_key_events() {
+        let mut stream = Vec::new();
+        // MSC_SCAN, press A, SYN_REPORT, repeat, release
+        stream.extend(input_event(0x04, 0x04, 0x70004));
+        stream.extend(input_event(EV_KEY, 30, 1));
+        stream.extend(input_event(0x00, 0, 0));
+        stream.extend(input_event(EV_KEY, 30, KEY_REPEAT));
+        stream.extend(input_event(EV_KEY, 30, 0));
+        // Truncated trailing event is ignored
+        stream.extend(&input_event(EV_KEY, 31, 1)[..4]);
+
+        let events: Vec<KeyEvent> = KeyEventReader::new(Cursor::new(stream))
+            .collect::<Result<_>>()
+            .unwrap();
+
+        assert_eq!(
+            events,
+            vec![
+                KeyEvent {
+                    keycode: 30,
+                    pressed: true
+                },
+                KeyEvent {
+                    keycode: 30,
+                    pressed: false
+                },
+            ]
+        );
+        assert_eq!(events[0].key_index(), Layout::default().key_index("a").ok());
+    }
+}
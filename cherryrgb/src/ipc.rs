@@ -0,0 +1,659 @@
+use crate::{
+    color_profile_from_value,
+    extensions::OwnRGB8,
+    models::{Brightness, CustomKeyLeds, LightingConfig, LightingMode, Speed},
+    transport::Transport,
+    CherryKeyboard, Error, Result,
+};
+use serde_json::{json, Map, Value};
+use std::{
+    convert::TryFrom,
+    env, fs,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How often `serve` checks for new clients and the stop flag while idle
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+/// Clients not sending a request for this long are disconnected
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Socket path used if none is configured: `$XDG_RUNTIME_DIR/cherryrgbd.sock`
+///
+/// `None` if `XDG_RUNTIME_DIR` is not set, there is no other directory only
+/// the current user can access.
+pub fn default_socket_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("cherryrgbd.sock"))
+}
+
+/// Listen on `path`, accessible by the current user only
+///
+/// A socket left behind by a previous daemon of the same user is replaced,
+/// anything else at `path` is left alone and reported as error.
+pub fn bind<P: AsRef<Path>>(path: P) -> Result<UnixListener> {
+    let path = path.as_ref();
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::Daemon(format!(
+                "Daemon already listening on {}",
+                path.display()
+            )));
+        }
+
+        let uid = unsafe { libc::geteuid() };
+        if !metadata.file_type().is_socket() || metadata.uid() != uid {
+            return Err(Error::Daemon(format!(
+                "{} exists and is not a socket of the current user",
+                path.display()
+            )));
+        }
+        fs::remove_file(path)?;
+    }
+
+    // Create the socket with mode 0600 right away instead of restricting it
+    // afterwards, so it is never reachable by other users. The umask is process
+    // wide, the daemon binds before starting any other thread.
+    let previous = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+
+    Ok(listener?)
+}
+
+/// Command sent to the daemon, one JSON object per line
+///
+/// ```json
+/// {"command": "set_animation", "mode": "rain", "brightness": "full", "speed": "slow", "color": "00ff00", "rainbow": false}
+/// {"command": "set_custom_colors", "colors": {"42": "ff0000"}}
+/// {"command": "set_color_profile", "colors": {"esc": "ff0000", "42": "00ff00"}}
+/// {"command": "set_brightness", "brightness": "low"}
+/// {"command": "reset_custom_colors"}
+/// {"command": "ping"}
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    SetAnimation {
+        mode: LightingMode,
+        brightness: Brightness,
+        speed: Speed,
+        color: OwnRGB8,
+        rainbow: bool,
+    },
+    /// Custom colors by key index
+    SetCustomColors(CustomKeyLeds),
+    /// Color profile, key names are resolved in the layout of the daemon's keyboard
+    SetColorProfile(Map<String, Value>),
+    SetBrightness(Brightness),
+    ResetCustomColors,
+    Ping,
+}
+
+/// Get string field `name` of a request
+fn string_field<'a>(value: &'a Value, name: &str) -> Result<&'a str> {
+    value
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::InvalidRequest(format!("Missing or invalid '{name}'")))
+}
+
+/// Parse string field `name` of a request, e.g. a `LightingMode`
+fn parse_field<T: FromStr>(value: &Value, name: &str) -> Result<T> {
+    let field = string_field(value, name)?;
+
+    field
+        .parse()
+        .map_err(|_| Error::InvalidRequest(format!("Invalid {name} '{field}'")))
+}
+
+impl Request {
+    /// Set the colors of a color profile file, see `read_color_profile`
+    pub fn color_profile(color_profile: &str) -> Result<Self> {
+        match serde_json::from_str(color_profile)? {
+            Value::Object(colors) => Ok(Request::SetColorProfile(colors)),
+            _ => Err(Error::InvalidProfile(
+                "No valid colors found in color profile.".to_string(),
+            )),
+        }
+    }
+
+    /// Encode as single line JSON
+    pub fn to_json(&self) -> String {
+        let value = match self {
+            Request::SetAnimation {
+                mode,
+                brightness,
+                speed,
+                color,
+                rainbow,
+            } => json!({
+                "command": "set_animation",
+                "mode": mode.as_ref(),
+                "brightness": brightness.as_ref(),
+                "speed": speed.as_ref(),
+                "color": color.to_hex(),
+                "rainbow": rainbow,
+            }),
+            Request::SetCustomColors(key_leds) => {
+                // Keys which are off are implied
+                let colors: Map<String, Value> = key_leds
+                    .key_leds()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, color)| **color != OwnRGB8::default())
+                    .map(|(index, color)| (index.to_string(), color.to_hex().into()))
+                    .collect();
+                json!({ "command": "set_custom_colors", "colors": colors })
+            }
+            Request::SetColorProfile(colors) => {
+                json!({ "command": "set_color_profile", "colors": colors })
+            }
+            Request::SetBrightness(brightness) => {
+                json!({ "command": "set_brightness", "brightness": brightness.as_ref() })
+            }
+            Request::ResetCustomColors => json!({ "command": "reset_custom_colors" }),
+            Request::Ping => json!({ "command": "ping" }),
+        };
+
+        value.to_string()
+    }
+
+    /// Decode from JSON
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: Value =
+            serde_json::from_str(json).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+
+        match string_field(&value, "command")? {
+            "set_animation" => Ok(Request::SetAnimation {
+                mode: parse_field(&value, "mode")?,
+                brightness: parse_field(&value, "brightness")?,
+                speed: parse_field(&value, "speed")?,
+                color: parse_field(&value, "color")?,
+                rainbow: value
+                    .get("rainbow")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            }),
+            "set_custom_colors" => {
+                let colors = value
+                    .get("colors")
+                    .ok_or_else(|| Error::InvalidRequest("Missing 'colors'".to_string()))?;
+
+                Ok(Request::SetCustomColors(CustomKeyLeds::try_from(
+                    color_profile_from_value(colors, None)?,
+                )?))
+            }
+            "set_color_profile" => {
+                let colors = value
+                    .get("colors")
+                    .and_then(Value::as_object)
+                    .ok_or_else(|| {
+                        Error::InvalidRequest("Missing or invalid 'colors'".to_string())
+                    })?;
+
+                Ok(Request::SetColorProfile(colors.clone()))
+            }
+            "set_brightness" => Ok(Request::SetBrightness(parse_field(&value, "brightness")?)),
+            "reset_custom_colors" => Ok(Request::ResetCustomColors),
+            "ping" => Ok(Request::Ping),
+            command => Err(Error::InvalidRequest(format!(
+                "Unknown command '{command}'"
+            ))),
+        }
+    }
+
+    /// Execute request on `keyboard`
+    ///
+    /// `lighting` tracks the lighting last applied through requests, which
+    /// `SetBrightness` changes the brightness of.
+    pub fn execute<T: Transport>(
+        &self,
+        keyboard: &CherryKeyboard<T>,
+        lighting: &mut Option<LightingConfig>,
+    ) -> Result<()> {
+        match self {
+            Request::SetAnimation {
+                mode,
+                brightness,
+                speed,
+                color,
+                rainbow,
+            } => {
+                let config = LightingConfig::Animation {
+                    mode: mode.clone(),
+                    brightness: brightness.clone(),
+                    speed: speed.clone(),
+                    color: color.clone(),
+                    rainbow: *rainbow,
+                };
+                keyboard.apply_lighting(&config)?;
+                *lighting = Some(config);
+            }
+            Request::SetCustomColors(key_leds) => {
+                let config = LightingConfig::CustomColors(key_leds.clone());
+                keyboard.apply_lighting(&config)?;
+                *lighting = Some(config);
+            }
+            Request::SetColorProfile(colors) => {
+                let profile_keys = color_profile_from_value(
+                    &Value::Object(colors.clone()),
+                    Some(&keyboard.layout()),
+                )?;
+                let config = LightingConfig::CustomColors(CustomKeyLeds::try_from(profile_keys)?);
+                keyboard.apply_lighting(&config)?;
+                *lighting = Some(config);
+            }
+            Request::SetBrightness(brightness) => {
+                let current = lighting.as_mut().ok_or_else(|| {
+                    Error::InvalidRequest(
+                        "Current lighting is unknown, set an animation or custom colors first"
+                            .to_string(),
+                    )
+                })?;
+                keyboard.set_brightness(brightness.clone(), current)?;
+                if let LightingConfig::Animation {
+                    brightness: current_brightness,
+                    ..
+                } = current
+                {
+                    *current_brightness = brightness.clone();
+                }
+            }
+            Request::ResetCustomColors => {
+                keyboard.reset_custom_colors()?;
+                *lighting = None;
+            }
+            Request::Ping => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Daemon answer to a single request
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Ok,
+    Error(String),
+}
+
+impl Response {
+    /// Encode as single line JSON: `{"status": "ok"}` or `{"status": "error", "message": ...}`
+    pub fn to_json(&self) -> String {
+        let value = match self {
+            Response::Ok => json!({ "status": "ok" }),
+            Response::Error(message) => json!({ "status": "error", "message": message }),
+        };
+
+        value.to_string()
+    }
+
+    /// Decode from JSON
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: Value =
+            serde_json::from_str(json).map_err(|e| Error::InvalidRequest(e.to_string()))?;
+
+        match string_field(&value, "status")? {
+            "ok" => Ok(Response::Ok),
+            "error" => Ok(Response::Error(
+                string_field(&value, "message")?.to_string(),
+            )),
+            status => Err(Error::InvalidRequest(format!("Unknown status '{status}'"))),
+        }
+    }
+}
+
+/// Keyboard served by the daemon, opened on demand and again after it was unplugged
+struct Connection<T: Transport, F: FnMut() -> Result<CherryKeyboard<T>>> {
+    open: F,
+    keyboard: Option<CherryKeyboard<T>>,
+    /// Lighting last applied through requests
+    lighting: Option<LightingConfig>,
+}
+
+impl<T: Transport, F: FnMut() -> Result<CherryKeyboard<T>>> Connection<T, F> {
+    fn new(open: F) -> Self {
+        Self {
+            open,
+            keyboard: None,
+            lighting: None,
+        }
+    }
+
+    /// Execute `request`, opening the keyboard first if necessary
+    fn execute(&mut self, request: &Request) -> Result<()> {
+        let keyboard = match self.keyboard.take() {
+            Some(keyboard) => keyboard,
+            None => (self.open)()?,
+        };
+
+        let result = request.execute(&keyboard, &mut self.lighting);
+        match result {
+            Err(Error::NoDeviceFound) => {
+                log::warn!("Keyboard is gone, reopening it for the next request")
+            }
+            _ => self.keyboard = Some(keyboard),
+        }
+
+        result
+    }
+
+    /// Hand the keyboard back, if it is open
+    fn close(self) -> Result<()> {
+        match self.keyboard {
+            Some(keyboard) => keyboard.close(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Error with all its causes, e.g. "I/O error: Broken pipe"
+fn error_message(error: &Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+
+    message
+}
+
+/// Answer requests of a single client until it disconnects, goes idle or `stop` is set
+fn handle_client<T: Transport, F: FnMut() -> Result<CherryKeyboard<T>>>(
+    connection: &mut Connection<T, F>,
+    stream: &UnixStream,
+    stop: &AtomicBool,
+) -> Result<()> {
+    // Wake up regularly while waiting for a request, so `stop` is noticed
+    stream.set_read_timeout(Some(ACCEPT_INTERVAL))?;
+    let mut reader = BufReader::new(stream);
+    let mut writer = stream;
+    let mut line = Vec::new();
+    let mut last_request = Instant::now();
+
+    while !stop.load(Ordering::SeqCst) {
+        // A partially read line stays in `line` when the read times out
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if last_request.elapsed() >= CLIENT_TIMEOUT {
+                    return Err(Error::Daemon("Client was idle for too long".to_string()));
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        last_request = Instant::now();
+
+        let request = String::from_utf8_lossy(&line).trim().to_owned();
+        line.clear();
+        if request.is_empty() {
+            continue;
+        }
+
+        log::debug!("<< {}", request);
+        let response = match Request::from_json(&request).and_then(|r| connection.execute(&r)) {
+            Ok(()) => Response::Ok,
+            Err(e) => {
+                let message = error_message(&e);
+                log::warn!("Request failed: {}", message);
+                Response::Error(message)
+            }
+        };
+        writeln!(writer, "{}", response.to_json())?;
+    }
+
+    Ok(())
+}
+
+/// Serve clients connecting to `listener`, one at a time, until `stop` is set
+///
+/// The keyboard is opened by `open` on start and again on the next request after
+/// it was unplugged, it is closed once `stop` is set.
+pub fn serve<T: Transport, F: FnMut() -> Result<CherryKeyboard<T>>>(
+    open: F,
+    listener: &UnixListener,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let mut connection = Connection::new(open);
+    match (connection.open)() {
+        Ok(keyboard) => connection.keyboard = Some(keyboard),
+        Err(e) => log::warn!(
+            "Failed to open keyboard, retrying on the first request: {}",
+            e
+        ),
+    }
+
+    // Poll instead of blocking in accept, so `stop` is noticed
+    listener.set_nonblocking(true)?;
+
+    while !stop.load(Ordering::SeqCst) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_INTERVAL);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        if let Err(e) = handle_client(&mut connection, &stream, stop) {
+            log::warn!("Client connection failed: {}", e);
+        }
+    }
+
+    connection.close()
+}
+
+/// Client of a running daemon
+pub struct DaemonClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl DaemonClient {
+    /// Connect to the daemon listening on `path`
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let writer = UnixStream::connect(path)?;
+        let reader = BufReader::new(writer.try_clone()?);
+
+        Ok(Self { reader, writer })
+    }
+
+    /// Send request and wait for the daemon to execute it
+    pub fn send(&mut self, request: &Request) -> Result<()> {
+        writeln!(self.writer, "{}", request.to_json())?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(Error::Daemon("Connection closed".to_string()));
+        }
+
+        match Response::from_json(&line)? {
+            Response::Ok => Ok(()),
+            Response::Error(message) => Err(Error::Daemon(message)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockKeyboard;
+    use rgb::RGB8;
+    use std::{io::Read, net::Shutdown, os::unix::fs::PermissionsExt};
+
+    #[test]
+    fn request_json_roundtrip() {
+        let mut keys = CustomKeyLeds::new();
+        keys.set_led(42, RGB8::new(0xff, 0, 0)).unwrap();
+
+        let requests = vec![
+            Request::SetAnimation {
+                mode: LightingMode::WaveMid,
+                brightness: Brightness::High,
+                speed: Speed::VerySlow,
+                color: OwnRGB8::new(0x12, 0x34, 0x56),
+                rainbow: true,
+            },
+            Request::SetCustomColors(keys),
+            Request::color_profile(r#"{"esc": "ff0000", "42": "00ff00"}"#).unwrap(),
+            Request::SetBrightness(Brightness::Low),
+            Request::ResetCustomColors,
+            Request::Ping,
+        ];
+
+        for request in requests {
+            assert_eq!(Request::from_json(&request.to_json()).unwrap(), request);
+        }
+
+        assert_eq!(
+            Request::SetBrightness(Brightness::Off).to_json(),
+            r#"{"brightness":"off","command":"set_brightness"}"#
+        );
+        assert!(Request::from_json(r#"{"command": "explode"}"#).is_err());
+        assert!(Request::from_json(r#"{"command": "set_brightness"}"#).is_err());
+        // Names are resolved by the daemon, which knows the layout
+        assert!(Request::from_json(
+            r#"{"command": "set_custom_colors", "colors": {"esc": "ff0000"}}"#
+        )
+        .is_err());
+        assert!(Request::color_profile("[]").is_err());
+    }
+
+    #[test]
+    fn serve_client() {
+        let mut connection = Connection::new(|| {
+            Ok(CherryKeyboard::from_transport(MockKeyboard::new()).with_retries(0))
+        });
+        let (mut client, server) = UnixStream::pair().unwrap();
+
+        let requests = [
+            Request::Ping,
+            // Brightness is refused until the lighting is known
+            Request::SetBrightness(Brightness::Low),
+            Request::SetAnimation {
+                mode: LightingMode::Rain,
+                brightness: Brightness::Full,
+                speed: Speed::Fast,
+                color: OwnRGB8::new(0, 0xff, 0),
+                rainbow: false,
+            },
+            Request::SetBrightness(Brightness::Low),
+        ];
+        for request in &requests {
+            writeln!(client, "{}", request.to_json()).unwrap();
+        }
+        writeln!(client, "not json").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        handle_client(&mut connection, &server, &AtomicBool::new(false)).unwrap();
+        drop(server);
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        let responses: Vec<Response> = responses
+            .lines()
+            .map(|line| Response::from_json(line).unwrap())
+            .collect();
+
+        assert_eq!(responses[0], Response::Ok);
+        assert!(matches!(responses[1], Response::Error(_)));
+        assert_eq!(responses[2..4], [Response::Ok, Response::Ok]);
+        assert!(matches!(responses[4], Response::Error(_)));
+        assert!(matches!(
+            connection.lighting,
+            Some(LightingConfig::Animation {
+                brightness: Brightness::Low,
+                ..
+            })
+        ));
+
+        let state = connection.keyboard.unwrap().fetch_device_state().unwrap();
+        assert_eq!(state.brightness, Some(Brightness::Low));
+        assert_eq!(state.mode, Some(LightingMode::Rain));
+    }
+
+    #[test]
+    fn color_profile_in_keyboard_layout() {
+        let g80 = crate::DeviceModel::from_product_id(0x00dd);
+        let keyboard = CherryKeyboard::from_transport(MockKeyboard::new())
+            .with_model(g80)
+            .with_retries(0);
+        let mut lighting = None;
+
+        Request::color_profile(r#"{"esc": "ff0000"}"#)
+            .unwrap()
+            .execute(&keyboard, &mut lighting)
+            .unwrap();
+        assert_eq!(
+            keyboard.get_custom_colors().unwrap().get_led(0),
+            Some(&OwnRGB8::new(0xff, 0, 0))
+        );
+
+        // Tenkeyless, no numpad
+        assert!(matches!(
+            Request::color_profile(r#"{"numpad_enter": "ff0000"}"#)
+                .unwrap()
+                .execute(&keyboard, &mut lighting),
+            Err(Error::InvalidProfile(_))
+        ));
+    }
+
+    /// Transport of a keyboard that was unplugged
+    struct Unplugged;
+
+    impl Transport for Unplugged {
+        fn send_report(&self, _report: &crate::Report, _timeout: Duration) -> Result<()> {
+            Err(Error::NoDeviceFound)
+        }
+
+        fn recv_report(&self, _timeout: Duration) -> Result<crate::Report> {
+            Err(Error::NoDeviceFound)
+        }
+    }
+
+    #[test]
+    fn reopen_unplugged_keyboard() {
+        let mut opened = 0;
+        let mut connection = Connection::new(|| {
+            opened += 1;
+            Ok(CherryKeyboard::from_transport(Unplugged))
+        });
+
+        for _ in 0..2 {
+            assert!(matches!(
+                connection.execute(&Request::ResetCustomColors),
+                Err(Error::NoDeviceFound)
+            ));
+            assert!(connection.keyboard.is_none());
+        }
+        connection.close().unwrap();
+        assert_eq!(opened, 2);
+    }
+
+    #[test]
+    fn bind_user_only() {
+        let path = env::temp_dir().join(format!("cherryrgbd-test-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let listener = bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+}
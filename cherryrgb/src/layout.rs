@@ -0,0 +1,292 @@
+use crate::{
+    devices::{DeviceModel, FormFactor},
+    models::TOTAL_KEYS,
+    Error, Result,
+};
+use std::str::FromStr;
+
+/// Physical key layout standard
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LayoutVariant {
+    /// US style: wide backslash above a single row enter
+    #[default]
+    Ansi,
+    /// European style: tall enter, extra keys next to it and next to left shift
+    Iso,
+}
+
+/// Which layouts a key exists in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Presence {
+    All,
+    Ansi,
+    Iso,
+    /// Numpad, missing on tenkeyless keyboards
+    FullSize,
+}
+
+/// Key in the grid: name, Linux keycode and the layouts it exists in
+#[derive(Clone, Copy, Debug)]
+struct Key {
+    name: &'static str,
+    keycode: u16,
+    presence: Presence,
+}
+
+const fn key(name: &'static str, keycode: u16) -> Key {
+    Key {
+        name,
+        keycode,
+        presence: Presence::All,
+    }
+}
+
+const fn numpad(name: &'static str, keycode: u16) -> Key {
+    Key {
+        presence: Presence::FullSize,
+        ..key(name, keycode)
+    }
+}
+
+const fn ansi(name: &'static str, keycode: u16) -> Key {
+    Key {
+        presence: Presence::Ansi,
+        ..key(name, keycode)
+    }
+}
+
+const fn iso(name: &'static str, keycode: u16) -> Key {
+    Key {
+        presence: Presence::Iso,
+        ..key(name, keycode)
+    }
+}
+
+/// Grid position without a key
+const NONE: Key = key("", 0);
+
+/// Key indices per row of the key index grid
+///
+/// Key indices are assumed to follow the physical layout row by row, with
+/// gaps where a row has fewer keys. Key names, keycodes and the positions
+/// effects render keys at all rely on this, it is not verified against hardware.
+pub const GRID_COLUMNS: usize = 21;
+/// Rows of the key index grid, see `GRID_COLUMNS`
+pub const GRID_ROWS: usize = TOTAL_KEYS / GRID_COLUMNS;
+
+/// Keys by position in the key index grid
+#[rustfmt::skip]
+const KEY_GRID: [[Key; GRID_COLUMNS]; GRID_ROWS] = [
+    [
+        key("esc", 1), NONE, key("f1", 59), key("f2", 60), key("f3", 61), key("f4", 62),
+        key("f5", 63), key("f6", 64), key("f7", 65), key("f8", 66), key("f9", 67),
+        key("f10", 68), key("f11", 87), key("f12", 88), key("print_screen", 99),
+        key("scroll_lock", 70), key("pause", 119), NONE, NONE, NONE, NONE,
+    ],
+    [
+        key("grave", 41), key("digit_1", 2), key("digit_2", 3), key("digit_3", 4),
+        key("digit_4", 5), key("digit_5", 6), key("digit_6", 7), key("digit_7", 8),
+        key("digit_8", 9), key("digit_9", 10), key("digit_0", 11), key("minus", 12),
+        key("equal", 13), key("backspace", 14), key("insert", 110), key("home", 102),
+        key("page_up", 104), numpad("num_lock", 69), numpad("numpad_divide", 98),
+        numpad("numpad_multiply", 55), numpad("numpad_minus", 74),
+    ],
+    [
+        key("tab", 15), key("q", 16), key("w", 17), key("e", 18), key("r", 19), key("t", 20),
+        key("y", 21), key("u", 22), key("i", 23), key("o", 24), key("p", 25),
+        key("left_bracket", 26), key("right_bracket", 27), ansi("backslash", 43),
+        key("delete", 111), key("end", 107), key("page_down", 109), numpad("numpad_7", 71),
+        numpad("numpad_8", 72), numpad("numpad_9", 73), numpad("numpad_plus", 78),
+    ],
+    [
+        key("caps_lock", 58), key("a", 30), key("s", 31), key("d", 32), key("f", 33),
+        key("g", 34), key("h", 35), key("j", 36), key("k", 37), key("l", 38),
+        key("semicolon", 39), key("apostrophe", 40), iso("hash", 43), key("enter", 28), NONE,
+        NONE, NONE, numpad("numpad_4", 75), numpad("numpad_5", 76), numpad("numpad_6", 77),
+        NONE,
+    ],
+    [
+        key("left_shift", 42), iso("iso_backslash", 86), key("z", 44), key("x", 45),
+        key("c", 46), key("v", 47), key("b", 48), key("n", 49), key("m", 50),
+        key("comma", 51), key("dot", 52), key("slash", 53), NONE, key("right_shift", 54), NONE,
+        key("up", 103), NONE, numpad("numpad_1", 79), numpad("numpad_2", 80),
+        numpad("numpad_3", 81), numpad("numpad_enter", 96),
+    ],
+    [
+        key("left_ctrl", 29), key("left_meta", 125), key("left_alt", 56), NONE, NONE, NONE,
+        key("space", 57), NONE, NONE, NONE, key("right_alt", 100), key("right_meta", 126),
+        key("menu", 127), key("right_ctrl", 97), key("left", 105), key("down", 108),
+        key("right", 106), NONE, numpad("numpad_0", 82), numpad("numpad_dot", 83), NONE,
+    ],
+];
+
+/// All grid positions with a key, with their key index
+fn grid_keys() -> impl Iterator<Item = (usize, &'static Key)> {
+    KEY_GRID
+        .iter()
+        .flatten()
+        .enumerate()
+        .filter(|(_, key)| !key.name.is_empty())
+}
+
+/// Keys of a keyboard, mapping key names and Linux keycodes to key (LED) indices
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Layout {
+    pub tenkeyless: bool,
+    pub variant: LayoutVariant,
+}
+
+impl Layout {
+    pub const FULL_SIZE_ANSI: Layout = Layout {
+        tenkeyless: false,
+        variant: LayoutVariant::Ansi,
+    };
+    pub const FULL_SIZE_ISO: Layout = Layout {
+        tenkeyless: false,
+        variant: LayoutVariant::Iso,
+    };
+    pub const TENKEYLESS_ANSI: Layout = Layout {
+        tenkeyless: true,
+        variant: LayoutVariant::Ansi,
+    };
+    pub const TENKEYLESS_ISO: Layout = Layout {
+        tenkeyless: true,
+        variant: LayoutVariant::Iso,
+    };
+
+    /// Layout of a known model, `None` if it is not a keyboard
+    pub fn for_model(model: &DeviceModel) -> Option<Layout> {
+        let tenkeyless = match model.form_factor {
+            FormFactor::FullSize => false,
+            FormFactor::Tenkeyless => true,
+            FormFactor::Mouse => return None,
+        };
+
+        Some(Layout {
+            tenkeyless,
+            variant: model.layout_variant,
+        })
+    }
+
+    fn has(&self, key: &Key) -> bool {
+        match key.presence {
+            Presence::All => true,
+            Presence::Ansi => self.variant == LayoutVariant::Ansi,
+            Presence::Iso => self.variant == LayoutVariant::Iso,
+            Presence::FullSize => !self.tenkeyless,
+        }
+    }
+
+    /// Names and key indices of all keys in this layout, ordered by index
+    pub fn keys(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        grid_keys()
+            .filter(move |(_, key)| self.has(key))
+            .map(|(index, key)| (key.name, index))
+    }
+
+    /// Key index of a key name, e.g. "w" or "numpad_enter"
+    ///
+    /// Names are matched case insensitively, keys missing in this layout are unknown.
+    pub fn key_index(&self, name: &str) -> Result<usize> {
+        self.keys()
+            .find(|(key_name, _)| key_name.eq_ignore_ascii_case(name))
+            .map(|(_, index)| index)
+            .ok_or_else(|| Error::UnknownKey(name.to_string()))
+    }
+
+    /// Name of the key at provided index
+    pub fn key_name(&self, key_index: usize) -> Option<&'static str> {
+        self.keys()
+            .find(|(_, index)| *index == key_index)
+            .map(|(name, _)| name)
+    }
+
+    /// Key index of a Linux keycode, e.g. 30 (`KEY_A`)
+    pub fn key_index_from_keycode(&self, keycode: u16) -> Option<usize> {
+        grid_keys()
+            .find(|(_, key)| key.keycode == keycode && self.has(key))
+            .map(|(index, _)| index)
+    }
+}
+
+impl FromStr for Layout {
+    type Err = Error;
+
+    /// Parse "full-size-ansi", "full-size-iso", "tkl-ansi" or "tkl-iso"
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full-size-ansi" => Ok(Layout::FULL_SIZE_ANSI),
+            "full-size-iso" => Ok(Layout::FULL_SIZE_ISO),
+            "tkl-ansi" => Ok(Layout::TENKEYLESS_ANSI),
+            "tkl-iso" => Ok(Layout::TENKEYLESS_ISO),
+            _ => Err(Error::UnknownLayout(s.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names() {
+        let layout = Layout::FULL_SIZE_ANSI;
+
+        assert_eq!(layout.key_index("esc").unwrap(), 0);
+        assert_eq!(layout.key_index("W").unwrap(), 2 * GRID_COLUMNS + 2);
+        assert_eq!(
+            layout.key_index("numpad_enter").unwrap(),
+            5 * GRID_COLUMNS - 1
+        );
+        assert_eq!(layout.key_name(GRID_COLUMNS + 1), Some("digit_1"));
+        assert!(matches!(
+            layout.key_index("hash"),
+            Err(Error::UnknownKey(_))
+        ));
+        assert!(layout.key_index("any").is_err());
+        assert!(Layout::TENKEYLESS_ANSI.key_index("numpad_enter").is_err());
+        assert_eq!(
+            Layout::TENKEYLESS_ISO.key_index("iso_backslash").unwrap(),
+            4 * GRID_COLUMNS + 1
+        );
+        assert_eq!(
+            Layout::FULL_SIZE_ISO.key_index("hash").unwrap(),
+            3 * GRID_COLUMNS + 12
+        );
+
+        // Names are unique
+        let mut names: Vec<&str> = grid_keys().map(|(_, key)| key.name).collect();
+        let count = names.len();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), count);
+    }
+
+    #[test]
+    fn keycodes() {
+        // KEY_BACKSLASH sits above enter on ANSI and next to it on ISO
+        assert_eq!(
+            Layout::FULL_SIZE_ANSI.key_index_from_keycode(43),
+            Layout::FULL_SIZE_ANSI.key_index("backslash").ok()
+        );
+        assert_eq!(
+            Layout::FULL_SIZE_ISO.key_index_from_keycode(43),
+            Layout::FULL_SIZE_ISO.key_index("hash").ok()
+        );
+        assert_eq!(Layout::TENKEYLESS_ANSI.key_index_from_keycode(96), None);
+        assert_eq!(Layout::FULL_SIZE_ANSI.key_index_from_keycode(0), None);
+        assert_eq!(Layout::FULL_SIZE_ANSI.key_index_from_keycode(240), None);
+    }
+
+    #[test]
+    fn model_layouts() {
+        let g80 = DeviceModel::from_product_id(0x00dd).unwrap();
+        assert_eq!(Layout::for_model(g80), Some(Layout::TENKEYLESS_ANSI));
+
+        let de = DeviceModel::from_product_id(0x01a6).unwrap();
+        assert_eq!(Layout::for_model(de), Some(Layout::FULL_SIZE_ISO));
+
+        let mouse = DeviceModel::from_product_id(0x01a4).unwrap();
+        assert_eq!(Layout::for_model(mouse), None);
+    }
+}
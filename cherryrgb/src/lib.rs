@@ -8,10 +8,12 @@
 //!
 //! // Optionally, filter for product id if you have more than one cherry device.
 //! let devices = cherryrgb::find_devices(Some(0x00dd)).unwrap();
-//! let (vendor_id, product_id) = devices.first().unwrap().to_owned();
-//! let keyboard = CherryKeyboard::new(vendor_id, product_id).unwrap();
+//! let device = devices.first().unwrap();
+//! println!("Using {}", device);
+//! let keyboard = CherryKeyboard::open(device).unwrap();
 //!
-//! keyboard.fetch_device_state().unwrap();
+//! let state = keyboard.fetch_device_state().unwrap();
+//! println!("Current mode: {:?}", state.mode);
 //! ```
 //!
 //! Set LED animation
@@ -51,32 +53,118 @@
 //! // Send packets to keyboard
 //! keyboard.set_custom_colors(keys).unwrap();
 //! ```
+//!
+//! Snapshot custom key colors and restore them later (experimental)
+//! ```rust no_run
+//! # let keyboard = cherryrgb::CherryKeyboard::new(0, 0).unwrap();
+//! let snapshot = keyboard.get_custom_colors().unwrap();
+//!
+//! keyboard.reset_custom_colors().unwrap();
+//!
+//! keyboard.set_custom_colors(snapshot).unwrap();
+//! ```
+//!
+//! Use the Linux hidraw interface instead of libusb, keeping the kernel driver attached
+//! ```rust no_run
+//! use cherryrgb::{CherryKeyboard, HidrawTransport};
+//!
+//! let transport = HidrawTransport::new(cherryrgb::CHERRY_USB_VID, 0x00dd).unwrap();
+//! let keyboard = CherryKeyboard::from_transport(transport);
+//!
+//! keyboard.reset_custom_colors().unwrap();
+//! ```
+//!
+//! Talk to an in-memory mock instead of real hardware
+//! ```rust
+//! use cherryrgb::{CherryKeyboard, MockKeyboard, Payload};
+//!
+//! let keyboard = CherryKeyboard::from_transport(MockKeyboard::new());
+//! keyboard.reset_custom_colors().unwrap();
+//!
+//! let packets = keyboard.transport().sent_packets();
+//! assert_eq!(packets.first().unwrap().payload(), &Payload::TransactionStart);
+//! ```
 
+mod devices;
+mod effects;
+mod error;
 mod extensions;
+mod heatmap;
+#[cfg(target_os = "linux")]
+mod hidraw;
+mod hotplug;
+mod input;
+#[cfg(unix)]
+mod ipc;
+mod layout;
+mod mock;
 mod models;
+mod stream;
+mod transport;
 
-use anyhow::{anyhow, Context, Result};
 use binrw::BinReaderExt;
-use models::ProfileKey;
+use models::{PayloadType, ProfileKey};
 use rgb::RGB8;
-use rusb::UsbContext;
 use serde_json::{self, Value};
-use std::{str::FromStr, time::Duration};
+use std::{
+    convert::TryFrom,
+    io::Read,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
 
 // Re-exports
+pub use devices::{
+    Backlight, Capabilities, DeviceDescriptor, DeviceModel, FormFactor, DEVICE_MODELS,
+};
+pub use effects::{
+    Effect, FadingTrail, GradientSweep, KeyBreathing, Plasma, ReactiveEffect, Ripple, TypingHeatmap,
+};
+pub use error::{Error, Result};
 pub use extensions::{OwnRGB8, ToVec};
+pub use heatmap::{ColorRamp, KeyPressCounts};
 pub use hex;
-pub use models::{Brightness, CustomKeyLeds, LightingMode, Packet, Payload, Speed};
+#[cfg(target_os = "linux")]
+pub use hidraw::{find_hidraw_nodes, HidrawTransport};
+pub use hotplug::{HotplugEvent, HotplugWatcher};
+#[cfg(target_os = "linux")]
+pub use input::find_input_nodes;
+pub use input::{
+    forward_key_events, keycode_to_key_index, KeyEvent, KeyEventReader, INPUT_EVENT_SIZE,
+};
+#[cfg(unix)]
+pub use ipc::{bind, default_socket_path, serve, DaemonClient, Request, Response};
+pub use layout::{Layout, LayoutVariant, GRID_COLUMNS, GRID_ROWS};
+pub use mock::MockKeyboard;
+pub use models::{
+    Brightness, CustomKeyLeds, DeviceInfo, DeviceState, FirmwareVersion, KeyMapping,
+    LightingConfig, LightingMode, Macro, MacroAction, MacroAssignment, MacroEvent, Packet, Payload,
+    Speed, UsbIdentity, UsbPollingRate,
+};
 pub use rgb;
 pub use rusb;
+pub use stream::CustomColorStream;
+pub use transport::{Report, Transport, UsbTransport, REPORT_SIZE};
 
 // Constants
 /// USB Vendor ID - Cherry GmbH
 pub const CHERRY_USB_VID: u16 = 0x046a;
 
-const INTERFACE_NUM: u8 = 1;
-const INTERRUPT_EP: u8 = 0x82;
-static TIMEOUT: Duration = Duration::from_millis(1000);
+/// Offset of the data in a read response (4 byte packet header + 4 byte payload header)
+pub(crate) const READ_DATA_OFFSET: usize = 8;
+/// Maximum data length of a single read request
+const READ_CHUNK_SIZE: usize = REPORT_SIZE - READ_DATA_OFFSET;
+
+/// Default timeout of a single report transfer
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Default number of retries after a transient failure
+pub const DEFAULT_RETRIES: u32 = 2;
+/// Default delay before the first retry, doubled for every further retry
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(50);
+/// Longest delay between two retries, however often the backoff was doubled
+pub const MAX_BACKOFF: Duration = Duration::from_secs(2);
 
 /// Calculate packet checksum (index 1 in payload)
 fn calc_checksum(payload_type: u8, data: &[u8]) -> u16 {
@@ -85,59 +173,215 @@ fn calc_checksum(payload_type: u8, data: &[u8]) -> u16 {
     sum
 }
 
-/// Return true, if supplied product id is not blacklisted
+/// Parse response and verify it acknowledges `payload_type` with a valid checksum
+///
+/// Magic and payload type are checked before parsing, so a garbled or foreign
+/// report is treated as transient like any other unexpected response.
+///
+/// The checksum is calculated over the whole report, as trailing padding does
+/// not contribute to it. For read responses, which carry more data than their
+/// request, this assumes the keyboard sums all data bytes as well.
+fn verify_response(payload_type: u8, response: &Report) -> Result<Packet<Payload>> {
+    if response[0] != 0x04 {
+        return Err(Error::BadMagic { got: response[0] });
+    }
+    let unexpected = Error::UnexpectedResponse {
+        expected: payload_type,
+        got: response[3],
+    };
+    if response[3] != payload_type {
+        return Err(unexpected);
+    }
+
+    let packet = std::io::Cursor::new(response)
+        .read_ne::<Packet<Payload>>()
+        .map_err(|e| {
+            log::debug!("Failed to parse response: {}", e);
+            unexpected
+        })?;
+
+    let calculated = calc_checksum(response[3], &response[4..]);
+    if calculated != packet.checksum() {
+        return Err(Error::ChecksumMismatch {
+            expected: calculated,
+            got: packet.checksum(),
+        });
+    }
+
+    Ok(packet)
+}
+
+/// Copy the data of a read response to its `data_offset` within `buf`
+///
+/// Read responses share the layout of `Payload::SetCustomLED`:
+/// `[data_len, data_offset (u16), padding, data...]`
+fn assemble_read_response(buf: &mut Vec<u8>, response: &Report) {
+    let data_len = response[4] as usize;
+    let data_offset = u16::from_le_bytes([response[5], response[6]]) as usize;
+    let data = &response[READ_DATA_OFFSET..(READ_DATA_OFFSET + data_len).min(REPORT_SIZE)];
+
+    if buf.len() < data_offset + data.len() {
+        buf.resize(data_offset + data.len(), 0);
+    }
+    buf[data_offset..data_offset + data.len()].copy_from_slice(data);
+}
+
+/// Return true, if supplied product id is not blacklisted and not in service / bootloader mode
 fn is_supported(product_id: u16) -> bool {
     let blacklist: Vec<u16> = vec![
         0xc122, // Cherry KC 1000
     ];
+    if let Some(model) = DeviceModel::from_bootloader_product_id(product_id) {
+        log::warn!(
+            "Skipping {} ({:04x}), it is in service / bootloader mode",
+            model.name,
+            product_id
+        );
+        return false;
+    }
     !blacklist.contains(&product_id)
 }
 
-/// Find supported Cherry USB keyboards and return collection of (vendor_id, product_id)
-pub fn find_devices(product_id: Option<u16>) -> Result<Vec<(u16, u16)>> {
+/// Warn about devices missing from the registry or never reported to work
+fn warn_untested(product_id: u16) {
+    match DeviceModel::from_product_id(product_id) {
+        Some(model) if !model.tested => log::warn!(
+            "{} ({:04x}) is untested, please report whether it works",
+            model.name,
+            product_id
+        ),
+        Some(_) => {}
+        None => log::warn!(
+            "Unknown Cherry device {:04x}, please report whether it works",
+            product_id
+        ),
+    }
+}
+
+/// Describe an attached device, without opening it to read the serial number
+fn describe_device<T: rusb::UsbContext>(
+    device: &rusb::Device<T>,
+    desc: &rusb::DeviceDescriptor,
+) -> DeviceDescriptor {
+    DeviceDescriptor {
+        vendor_id: desc.vendor_id(),
+        product_id: desc.product_id(),
+        bus_number: device.bus_number(),
+        address: device.address(),
+        port_numbers: device.port_numbers().unwrap_or_default(),
+        serial_number: None,
+        model: DeviceModel::from_product_id(desc.product_id()),
+    }
+}
+
+/// Read serial number of a device, if it has one and can be opened
+fn read_serial_number<T: rusb::UsbContext>(
+    device: &rusb::Device<T>,
+    desc: &rusb::DeviceDescriptor,
+) -> Option<String> {
+    device
+        .open()
+        .and_then(|handle| handle.read_serial_number_string_ascii(desc))
+        .ok()
+}
+
+/// Find supported Cherry USB keyboards, sorted by bus number and address
+pub fn find_devices(product_id: Option<u16>) -> Result<Vec<DeviceDescriptor>> {
     let devices = rusb::devices()?;
     // Search usb devices with VENDOR_ID of Cherry GmbH
     // If product_id is provided, filter for it too
-    let usb_ids: Vec<(u16, u16)> = devices
+    let mut found: Vec<DeviceDescriptor> = devices
         .iter()
-        .map(|dev| dev.device_descriptor().unwrap())
-        .filter(|desc| desc.vendor_id() == CHERRY_USB_VID)
-        .filter(|desc| is_supported(desc.product_id()))
-        .filter(|desc| match product_id {
+        .filter_map(|dev| match dev.device_descriptor() {
+            Ok(desc) => Some((dev, desc)),
+            Err(e) => {
+                log::warn!(
+                    "Skipping device on Bus {:03} Device {:03}, failed to read descriptor: {}",
+                    dev.bus_number(),
+                    dev.address(),
+                    e
+                );
+                None
+            }
+        })
+        .filter(|(_, desc)| desc.vendor_id() == CHERRY_USB_VID)
+        .filter(|(_, desc)| is_supported(desc.product_id()))
+        .filter(|(_, desc)| match product_id {
             Some(prod_id) => desc.product_id() == prod_id,
             None => true,
         })
-        .map(|desc| (desc.vendor_id(), desc.product_id()))
+        .map(|(dev, desc)| DeviceDescriptor {
+            serial_number: read_serial_number(&dev, &desc),
+            ..describe_device(&dev, &desc)
+        })
         .collect();
 
-    if usb_ids.is_empty() {
-        return Err(anyhow!("No matching devices found"));
+    for device in &found {
+        warn_untested(device.product_id);
+    }
+
+    if found.is_empty() {
+        return Err(Error::NoDeviceFound);
     }
 
-    Ok(usb_ids)
+    found.sort_by_key(|device| (device.bus_number, device.address));
+    Ok(found)
 }
 
 /// Reads the given color profile and returns a vector of `ProfileKey`.
+///
+/// Keys are given by index or by name, e.g. `{ "42": "ff0000", "esc": "00ff00" }`.
+/// Names are resolved in the default layout, see `read_color_profile_with_layout`.
 /// # Arguments
 /// * `color_profile` - Color profile content.
 pub fn read_color_profile(color_profile: &str) -> Result<Vec<ProfileKey>> {
+    read_color_profile_with_layout(color_profile, &Layout::default())
+}
+
+/// Like `read_color_profile`, resolving key names in `layout`, usually `CherryKeyboard::layout`
+/// # Arguments
+/// * `color_profile` - Color profile content.
+/// * `layout` - Layout of the keyboard the profile is applied to.
+pub fn read_color_profile_with_layout(
+    color_profile: &str,
+    layout: &Layout,
+) -> Result<Vec<ProfileKey>> {
     let v: Value = serde_json::from_str(color_profile)?;
 
+    color_profile_from_value(&v, Some(layout))
+}
+
+/// Parse an already decoded color profile: `{ "<key index or name>": "<hex color>", ... }`
+///
+/// Without a layout only key indices are accepted.
+fn color_profile_from_value(v: &Value, layout: Option<&Layout>) -> Result<Vec<ProfileKey>> {
     v.as_object().map_or(
-        Err(anyhow!(format!("No valid colors found in color profile."))),
+        Err(Error::InvalidProfile(
+            "No valid colors found in color profile.".to_string(),
+        )),
         |root| {
             root.iter()
                 .map(|(key, value)| {
-                    let key_index = key
-                        .parse::<usize>()
-                        .context(format!("parsing key index {}", key))?;
+                    // Either a key index or a key name, e.g. "w"
+                    let key_index = match (key.parse::<usize>(), layout) {
+                        (Ok(key_index), _) => key_index,
+                        (Err(_), Some(layout)) => layout.key_index(key).map_err(|e| {
+                            Error::InvalidProfile(format!("parsing key {}: {}", key, e))
+                        })?,
+                        (Err(e), None) => {
+                            return Err(Error::InvalidProfile(format!(
+                                "parsing key index {key}: {e}"
+                            )))
+                        }
+                    };
                     let color = value.as_str().map_or(
-                        Err(anyhow!(format!(
+                        Err(Error::InvalidProfile(format!(
                             "Invalid color for key with index {key_index}"
                         ))),
-                        |hex| match OwnRGB8::from_str(hex) {
-                            Ok(color) => Ok(color),
-                            Err(e) => Err(anyhow!(e)).context(format!("parsing hex color '{hex}'")),
+                        |hex| {
+                            OwnRGB8::from_str(hex).map_err(|e| {
+                                Error::InvalidProfile(format!("parsing hex color '{hex}': {e}"))
+                            })
                         },
                     )?;
                     Ok(ProfileKey::new(key_index, color))
@@ -147,110 +391,229 @@ pub fn read_color_profile(color_profile: &str) -> Result<Vec<ProfileKey>> {
     )
 }
 
-/// Holds a handle to the USB keyboard device
-pub struct CherryKeyboard {
-    device_handle: rusb::DeviceHandle<rusb::Context>,
+/// Reads the given macro file and returns the macros with the key they are assigned to.
+///
+/// The file holds an array of macros, their index is used as slot on the keyboard:
+/// `[{ "key": 117, "events": [{ "action": "press", "usage": 11, "delay": 20 }, ...] }]`
+/// # Arguments
+/// * `macro_file` - Macro file content.
+pub fn read_macro_file(macro_file: &str) -> Result<Vec<MacroAssignment>> {
+    let v: Value =
+        serde_json::from_str(macro_file).map_err(|e| Error::InvalidMacro(e.to_string()))?;
+
+    let number = |value: &Value, name: &str, index: usize| {
+        value.get(name).and_then(Value::as_u64).ok_or_else(|| {
+            Error::InvalidMacro(format!("Missing or invalid '{name}' in macro {index}"))
+        })
+    };
+
+    v.as_array()
+        .ok_or_else(|| Error::InvalidMacro("No macros found in macro file.".to_string()))?
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let key_index = number(entry, "key", index)? as usize;
+            if key_index >= models::TOTAL_KEYS {
+                return Err(Error::KeyIndexOutOfBounds(key_index));
+            }
+            let events = entry
+                .get("events")
+                .and_then(Value::as_array)
+                .ok_or_else(|| Error::InvalidMacro(format!("Missing events in macro {index}")))?
+                .iter()
+                .map(|event| {
+                    let action = event
+                        .get("action")
+                        .and_then(Value::as_str)
+                        .and_then(|action| MacroAction::from_str(action).ok())
+                        .ok_or_else(|| {
+                            Error::InvalidMacro(format!("Invalid action in macro {index}"))
+                        })?;
+                    let usage = u8::try_from(number(event, "usage", index)?).map_err(|_| {
+                        Error::InvalidMacro(format!("Usage out of range in macro {index}"))
+                    })?;
+                    let delay_ms = u16::try_from(number(event, "delay", index)?).map_err(|_| {
+                        Error::InvalidMacro(format!("Delay out of range in macro {index}"))
+                    })?;
+
+                    Ok(MacroEvent {
+                        action,
+                        usage,
+                        delay_ms,
+                    })
+                })
+                .collect::<Result<Vec<MacroEvent>>>()?;
+
+            Ok(MacroAssignment {
+                key_index,
+                sequence: Macro::new(events)?,
+            })
+        })
+        .collect()
 }
 
-impl CherryKeyboard {
+/// Holds a handle to the keyboard device, communicating through transport `T`
+pub struct CherryKeyboard<T: Transport = UsbTransport> {
+    transport: T,
+    timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    profile: Option<u8>,
+    model: Option<&'static DeviceModel>,
+    experimental: bool,
+}
+
+impl CherryKeyboard<UsbTransport> {
     /// Init USB device by verifying number of configurations and claiming appropriate interface
     pub fn new(vendor_id: u16, product_id: u16) -> Result<Self> {
-        let ctx = rusb::Context::new().context("Failed to create libusb context")?;
+        let transport = UsbTransport::new(vendor_id, product_id)?;
 
-        let mut device_handle = ctx
-            .open_device_with_vid_pid(vendor_id, product_id)
-            .context("Keyboard not found")?;
+        Ok(Self::from_transport(transport).with_model(DeviceModel::from_product_id(product_id)))
+    }
 
-        let device = device_handle.device();
-        let device_desc = device
-            .device_descriptor()
-            .context("Failed to read device descriptor")?;
-        let config_desc = device
-            .active_config_descriptor()
-            .context("Failed to get config descriptor")?;
+    /// Init the exact keyboard described by `descriptor`, as returned by `find_devices`
+    pub fn open(descriptor: &DeviceDescriptor) -> Result<Self> {
+        let transport = UsbTransport::from_descriptor(descriptor)?;
 
-        log::debug!(
-            "* Connected to: Bus {:03} Device {:03} ID {:04x}:{:04x}",
-            device.bus_number(),
-            device.address(),
-            device_desc.vendor_id(),
-            device_desc.product_id()
-        );
+        Ok(Self::from_transport(transport).with_model(descriptor.model))
+    }
+}
 
-        assert_eq!(device_desc.num_configurations(), 1);
-        assert_eq!(config_desc.num_interfaces(), 2);
+impl<T: Transport> CherryKeyboard<T> {
+    /// Wrap an already initialized transport
+    pub fn from_transport(transport: T) -> Self {
+        Self {
+            transport,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_BACKOFF,
+            profile: None,
+            model: None,
+            experimental: false,
+        }
+    }
 
-        // Skip kernel driver detachment for non-unix platforms
-        if cfg!(unix) {
-            let kernel_driver_active = device_handle
-                .kernel_driver_active(INTERFACE_NUM)
-                .context("kernel_driver_active")?;
+    /// Set timeout for every single report transfer
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
 
-            if kernel_driver_active {
-                device_handle
-                    .detach_kernel_driver(INTERFACE_NUM)
-                    .context("Failed to detach active kernel driver")?;
-            }
+    /// Set how often a command / transaction is retried after a transient failure
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set delay before the first retry, it is doubled for every further retry up to `MAX_BACKOFF`
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Write settings to onboard profile `slot` instead of the keyboard's current one (experimental)
+    ///
+    /// The slot is sent as the first byte of the settings block written by
+    /// `Payload::SetAnimation`, which is 0x00 in all captures. That this byte
+    /// selects the profile is a guess.
+    pub fn with_profile(mut self, slot: Option<u8>) -> Self {
+        self.profile = slot;
+        self
+    }
+
+    /// Set model of the keyboard, color commands are refused for models without RGB lighting
+    ///
+    /// Unknown models (`None`) are not restricted.
+    pub fn with_model(mut self, model: Option<&'static DeviceModel>) -> Self {
+        self.model = model;
+        self
+    }
+
+    /// Allow experimental commands
+    ///
+    /// Their payload types and formats are guesses which were never checked
+    /// against a keyboard, and some write to its onboard storage. Without
+    /// opting in, they fail with `Error::Experimental` before anything is sent.
+    pub fn with_experimental(mut self, enabled: bool) -> Self {
+        self.experimental = enabled;
+        self
+    }
+
+    /// Model of the keyboard, if known
+    pub fn model(&self) -> Option<&'static DeviceModel> {
+        self.model
+    }
+
+    /// Key layout of the keyboard, full size ANSI if the model is unknown
+    pub fn layout(&self) -> Layout {
+        self.model.and_then(Layout::for_model).unwrap_or_default()
+    }
+
+    /// Fail if the keyboard is known to have no RGB lighting
+    fn require_color(&self) -> Result<()> {
+        match self.model {
+            Some(model) if !model.supports_color() => Err(Error::UnsupportedFeature {
+                model: model.name,
+                feature: "RGB lighting",
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail if a profile slot was requested without enabling experimental commands
+    fn require_profile(&self) -> Result<()> {
+        if self.profile.is_some() {
+            self.require_experimental("Writing to an onboard profile")?;
+        }
+
+        Ok(())
+    }
+
+    /// Fail if experimental commands were not enabled, see `with_experimental`
+    fn require_experimental(&self, command: &'static str) -> Result<()> {
+        if !self.experimental {
+            return Err(Error::Experimental(command));
         }
 
-        device_handle
-            .claim_interface(INTERFACE_NUM)
-            .context("Failed to claim interface")?;
+        Ok(())
+    }
+
+    /// Access the underlying transport
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
 
-        Ok(Self { device_handle })
+    /// Hand the device back to the OS, reporting errors that `Drop` would only log
+    pub fn close(mut self) -> Result<()> {
+        self.transport.close()
     }
 
-    /// Writes a control packet first, then reads interrupt packet
-    fn send_payload(&self, payload: Payload) -> Result<Vec<u8>> {
+    /// Writes a control packet first, then reads and validates interrupt packet
+    fn send_payload(&self, payload: Payload) -> Result<Report> {
         let packet = Packet::new(payload);
+        let payload_type = packet.payload().payload_type();
 
         // Serialize and pad to 64 bytes
-        let mut packet_bytes = packet.clone().to_vec();
-        packet_bytes.resize(64, 0x00);
-
-        let mut response = [0u8; 64];
-        self.device_handle
-            .write_control(
-                rusb::request_type(
-                    rusb::Direction::Out,
-                    rusb::RequestType::Class,
-                    rusb::Recipient::Interface,
-                ),
-                0x09,          // Request - SET_REPORT
-                0x0204,        // Value - ReportId: 4, ReportType: Output
-                0x0001,        // Index
-                &packet_bytes, // Data
-                TIMEOUT,
-            )
-            .context("Control Write failure")?;
+        let mut packet_bytes = [0u8; REPORT_SIZE];
+        let serialized = packet.clone().to_vec();
+        packet_bytes[..serialized.len()].copy_from_slice(&serialized);
+
+        self.transport.send_report(&packet_bytes, self.timeout)?;
 
         log::debug!(
             ">> CONTROL TRANSFER {:?}\n>> {:?}\n",
-            hex::encode(&packet_bytes),
+            hex::encode(packet_bytes),
             packet,
         );
 
-        self.device_handle
-            .read_interrupt(
-                INTERRUPT_EP,  // Endpoint
-                &mut response, // read buffer
-                TIMEOUT,
-            )
-            .context("Interrupt read failure")?;
+        let response = self.transport.recv_report(self.timeout)?;
 
-        let detail_info = {
-            match std::io::Cursor::new(response).read_ne::<Packet<Payload>>() {
-                Ok(pkt) => format!("{:?} Checksum valid: {:?}", pkt, pkt.verify_checksum()),
-                Err(e) => format!("Failed to parse, err: {:?}", e),
-            }
-        };
-        log::debug!(
-            "<< INTERRUPT TRANSFER {:?}\n<< {}\n",
-            hex::encode(response),
-            detail_info
-        );
+        log::debug!("<< INTERRUPT TRANSFER {:?}\n", hex::encode(response));
+
+        let response_packet = verify_response(payload_type, &response)?;
+        log::debug!("<< {:?}\n", response_packet);
 
-        Ok(response.to_vec())
+        Ok(response)
     }
 
     /// Start RGB setting transaction
@@ -267,54 +630,107 @@ impl CherryKeyboard {
         Ok(())
     }
 
-    /// Just taken 1:1 from usb capture
-    pub fn fetch_device_state(&self) -> Result<()> {
-        log::trace!("Fetching device state - START");
-        self.start_transaction()?;
-        self.send_payload(Payload::Unknown3 { unk: 0x22 })?;
-        self.send_payload(Payload::Unknown7 {
-            data_len: 0x38,
-            data_offset: 0x00,
-        })?;
-        self.send_payload(Payload::Unknown7 {
-            data_len: 0x38,
-            data_offset: 0x38,
-        })?;
-        self.send_payload(Payload::Unknown7 {
-            data_len: 0x38,
-            data_offset: 0x70,
-        })?;
-        self.send_payload(Payload::Unknown7 {
-            data_len: 0x38,
-            data_offset: 0xA8,
-        })?;
-        self.send_payload(Payload::Unknown7 {
-            data_len: 0x38,
-            data_offset: 0xE0,
-        })?;
-        self.send_payload(Payload::Unknown7 {
-            data_len: 0x38,
-            data_offset: 0x118,
-        })?;
-        self.send_payload(Payload::Unknown7 {
-            data_len: 0x2A,
-            data_offset: 0x150,
-        })?;
-        self.send_payload(Payload::Unknown1B {
-            data_len: 0x38,
-            data_offset: 0x00,
-        })?;
-        self.send_payload(Payload::Unknown1B {
-            data_len: 0x38,
-            data_offset: 0x38,
+    /// Run `f`, repeating it according to the retry policy on transient errors
+    fn with_retry<R, F: FnMut() -> Result<R>>(&self, mut f: F) -> Result<R> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if e.is_transient() && attempt < self.retries => {
+                    let delay = self.retry_delay(attempt);
+                    attempt += 1;
+                    log::warn!(
+                        "{}, retrying in {:?} ({}/{})",
+                        e,
+                        delay,
+                        attempt,
+                        self.retries
+                    );
+                    thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Delay before retry number `attempt` (counting from 0)
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .checked_mul(2u32.saturating_pow(attempt))
+            .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+    }
+
+    /// Send a single payload outside of a transaction, with retries
+    fn send_payload_retry(&self, payload: Payload) -> Result<Report> {
+        self.with_retry(|| self.send_payload(payload.clone()))
+    }
+
+    /// Wrap `f` in start / end transaction
+    ///
+    /// On a transient error the whole transaction is restarted, so it is
+    /// either fully applied or the error is returned.
+    fn transaction<R, F: FnMut() -> Result<R>>(&self, mut f: F) -> Result<R> {
+        self.with_retry(|| {
+            self.start_transaction()?;
+            let result = f();
+            // Close the transaction before propagating an error of `f`
+            let end = self.end_transaction();
+            let result = result?;
+            end?;
+            Ok(result)
+        })
+    }
+
+    /// Read `total` bytes in chunks, `payload` builds the read request from (data_len, data_offset)
+    fn read_chunks<F: Fn(u8, u16) -> Payload>(&self, total: usize, payload: F) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(total);
+        for offset in (0..total).step_by(READ_CHUNK_SIZE) {
+            let len = READ_CHUNK_SIZE.min(total - offset);
+            let response = self.send_payload(payload(len as u8, offset as u16))?;
+            assemble_read_response(&mut data, &response);
+        }
+
+        Ok(data)
+    }
+
+    /// Read the settings block (mode, brightness, speed, color)
+    fn read_settings(&self) -> Result<Vec<u8>> {
+        let response = self.send_payload(Payload::Unknown3 {
+            unk: DeviceState::SETTINGS_SIZE as u8,
         })?;
-        self.send_payload(Payload::Unknown1B {
-            data_len: 0x0E,
-            data_offset: 0x70,
+        let mut settings = Vec::new();
+        assemble_read_response(&mut settings, &response);
+
+        Ok(settings)
+    }
+
+    /// Read and decode the current device state
+    ///
+    /// Request sequence is taken 1:1 from usb capture.
+    pub fn fetch_device_state(&self) -> Result<DeviceState> {
+        log::trace!("Fetching device state - START");
+        let state = self.transaction(|| {
+            let settings = self.read_settings()?;
+
+            let unknown7 =
+                self.read_chunks(DeviceState::UNKNOWN7_SIZE, |data_len, data_offset| {
+                    Payload::Unknown7 {
+                        data_len,
+                        data_offset,
+                    }
+                })?;
+            let unknown1b =
+                self.read_chunks(DeviceState::UNKNOWN1B_SIZE, |data_len, data_offset| {
+                    Payload::Unknown1B {
+                        data_len,
+                        data_offset: data_offset as u8,
+                    }
+                })?;
+
+            Ok(DeviceState::decode(settings, unknown7, unknown1b))
         })?;
-        self.end_transaction()?;
         log::trace!("Fetching device state - END");
-        Ok(())
+        log::debug!("Device state: {:?}", state);
+        Ok(state)
     }
 
     /// Set LED animation from different modes
@@ -326,17 +742,41 @@ impl CherryKeyboard {
         color: C,
         rainbow: bool,
     ) -> Result<()> {
+        self.require_color()?;
+        self.require_profile()?;
         log::trace!("Set LED animation - START");
-        self.start_transaction()?;
-        // Send main payload
+        let color = color.into();
+        self.transaction(|| {
+            self.send_animation(
+                mode.clone(),
+                brightness.clone(),
+                speed.clone(),
+                color.clone(),
+                rainbow,
+            )
+        })?;
+        log::trace!("Set LED animation - END");
+        Ok(())
+    }
+
+    /// Send the animation payloads, must be wrapped in a transaction
+    fn send_animation(
+        &self,
+        mode: LightingMode,
+        brightness: Brightness,
+        speed: Speed,
+        color: OwnRGB8,
+        rainbow: bool,
+    ) -> Result<()> {
+        // Send main payload, the first data byte presumably selects the profile
         self.send_payload(Payload::SetAnimation {
-            unknown: [0x09, 0x00, 0x00, 0x55, 0x00],
+            unknown: [0x09, 0x00, 0x00, 0x55, self.profile.unwrap_or(0x00)],
             mode,
             brightness,
             speed,
             pad: 0x0,
             rainbow: if rainbow { 1 } else { 0 },
-            color: color.into(),
+            color,
         })?;
         // Send unknown / ?static? bytes
         self.send_payload(Payload::SetAnimation {
@@ -349,28 +789,338 @@ impl CherryKeyboard {
             rainbow: 0x0,
             color: RGB8::new(0, 0, 0).into(),
         })?;
-
-        self.end_transaction()?;
-        log::trace!("Set LED animation - END");
         Ok(())
     }
 
+    /// Change brightness of `current`, the lighting last applied to the keyboard
+    ///
+    /// The keyboard is not asked for its current lighting, as the settings
+    /// it reports are not decoded reliably.
+    pub fn set_brightness(&self, brightness: Brightness, current: &LightingConfig) -> Result<()> {
+        match current {
+            LightingConfig::Animation {
+                mode,
+                speed,
+                color,
+                rainbow,
+                ..
+            } => self.set_led_animation(
+                mode.clone(),
+                brightness,
+                speed.clone(),
+                color.clone(),
+                *rainbow,
+            ),
+            LightingConfig::CustomColors(key_leds) => {
+                self.write_custom_colors(key_leds.clone(), brightness)
+            }
+        }
+    }
+
+    /// Apply a stored lighting setup, e.g. after the keyboard was replugged
+    pub fn apply_lighting(&self, config: &LightingConfig) -> Result<()> {
+        match config {
+            LightingConfig::Animation {
+                mode,
+                brightness,
+                speed,
+                color,
+                rainbow,
+            } => self.set_led_animation(
+                mode.clone(),
+                brightness.clone(),
+                speed.clone(),
+                color.clone(),
+                *rainbow,
+            ),
+            LightingConfig::CustomColors(key_leds) => self.set_custom_colors(key_leds.clone()),
+        }
+    }
+
     /// Set custom color for each individual key
+    ///
+    /// Custom colors are written in the sequence verified on hardware: the
+    /// Custom mode animation in its own transaction, then the `SetCustomLED`
+    /// chunks outside of any transaction, each retried on its own. Streamed
+    /// frames enter the mode once and then only send changed chunks.
     pub fn set_custom_colors(&self, key_leds: CustomKeyLeds) -> Result<()> {
+        self.write_custom_colors(key_leds, Brightness::Full)
+    }
+
+    fn write_custom_colors(&self, key_leds: CustomKeyLeds, brightness: Brightness) -> Result<()> {
         log::trace!("Set custom colors - START");
-        // Set custom led mode
+        let payloads = key_leds.get_payloads()?;
+        self.enter_custom_mode(brightness)?;
+        self.send_custom_led_chunks(&payloads)?;
+        log::trace!("Set custom colors - END");
+        Ok(())
+    }
+
+    /// Switch to custom color mode, see `set_custom_colors`
+    fn enter_custom_mode(&self, brightness: Brightness) -> Result<()> {
         self.set_led_animation(
             LightingMode::Custom,
-            Brightness::Full,
+            brightness,
             Speed::Slow,
             OwnRGB8::default(),
             false,
-        )?;
+        )
+    }
 
-        for payload in key_leds.get_payloads()? {
-            self.send_payload(payload)?;
+    /// Send `SetCustomLED` chunks after `enter_custom_mode`, returns the number sent
+    pub(crate) fn send_custom_led_chunks<'a, I>(&self, chunks: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = &'a Payload>,
+    {
+        let mut sent = 0;
+        for chunk in chunks {
+            self.send_payload_retry(chunk.clone())?;
+            sent += 1;
         }
-        log::trace!("Set custom colors - END");
+
+        Ok(sent)
+    }
+
+    /// Enter custom color mode at `brightness` once and stream frames, sending only changed chunks
+    ///
+    /// Much cheaper per frame than `set_custom_colors`, which switches the mode every time.
+    pub fn stream_custom_colors(&self, brightness: Brightness) -> Result<CustomColorStream<'_, T>> {
+        self.enter_custom_mode(brightness)?;
+
+        Ok(CustomColorStream::new(self))
+    }
+
+    /// Render frames of `effect` and push them to the keyboard at `fps` frames per second
+    ///
+    /// Frames are shown at `brightness`. Runs for `duration`, or forever if `None`, and stops early once `stop` is set.
+    /// Frames which cannot be sent in time are dropped, the effect keeps following
+    /// the wall clock.
+    pub fn play_effect<E: Effect + ?Sized>(
+        &self,
+        effect: &mut E,
+        brightness: Brightness,
+        fps: u32,
+        duration: Option<Duration>,
+        stop: &AtomicBool,
+    ) -> Result<()> {
+        self.run_frames(brightness, fps, duration, stop, |elapsed| {
+            effect.render(elapsed)
+        })
+    }
+
+    /// Like `play_effect`, feeding key presses read from `sources` to `effect`
+    ///
+    /// Sources are read in the background, see `forward_key_events`.
+    pub fn play_reactive_effect<E, R>(
+        &self,
+        effect: &mut E,
+        sources: Vec<KeyEventReader<R>>,
+        brightness: Brightness,
+        fps: u32,
+        duration: Option<Duration>,
+        stop: &AtomicBool,
+    ) -> Result<()>
+    where
+        E: ReactiveEffect + ?Sized,
+        R: Read + Send + 'static,
+    {
+        let receiver = forward_key_events(sources);
+        let layout = self.layout();
+
+        self.run_frames(brightness, fps, duration, stop, |elapsed| {
+            for event in receiver.try_iter().filter(|event| event.pressed) {
+                match layout.key_index_from_keycode(event.keycode) {
+                    Some(key_index) => effect.key_pressed(key_index, elapsed),
+                    None => log::debug!("No LED known for keycode {}", event.keycode),
+                }
+            }
+            effect.render(elapsed)
+        })
+    }
+
+    /// Stream frames returned by `frame` at `fps` frames per second, see `play_effect`
+    fn run_frames<F: FnMut(Duration) -> CustomKeyLeds>(
+        &self,
+        brightness: Brightness,
+        fps: u32,
+        duration: Option<Duration>,
+        stop: &AtomicBool,
+        mut frame: F,
+    ) -> Result<()> {
+        let frame_time = Duration::from_secs(1) / fps.max(1);
+        let mut stream = self.stream_custom_colors(brightness)?;
+        let start = Instant::now();
+        let mut next_frame = start;
+
+        loop {
+            let elapsed = start.elapsed();
+            stream.send_frame(frame(elapsed))?;
+
+            if duration.is_some_and(|duration| elapsed >= duration) || stop.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            next_frame += frame_time;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else {
+                log::trace!("Frame took longer than {:?}, dropping frames", frame_time);
+                next_frame = now;
+            }
+        }
+    }
+
+    /// Read the custom key colors currently stored on the keyboard (experimental)
+    ///
+    /// Relies on `Payload::Unknown7` returning the table written by `SetCustomLED`,
+    /// which is inferred from its size but not confirmed.
+    pub fn get_custom_colors(&self) -> Result<CustomKeyLeds> {
+        log::trace!("Get custom colors - START");
+        let data = self.transaction(|| {
+            self.read_chunks(DeviceState::UNKNOWN7_SIZE, |data_len, data_offset| {
+                Payload::Unknown7 {
+                    data_len,
+                    data_offset,
+                }
+            })
+        })?;
+        log::trace!("Get custom colors - END");
+        CustomKeyLeds::from_bytes(&data)
+    }
+
+    /// Read the key mapping currently stored on the keyboard
+    pub fn get_key_mapping(&self) -> Result<KeyMapping> {
+        log::trace!("Get key mapping - START");
+        let data = self.transaction(|| {
+            self.read_chunks(DeviceState::UNKNOWN1B_SIZE, |data_len, data_offset| {
+                Payload::Unknown1B {
+                    data_len,
+                    data_offset: data_offset as u8,
+                }
+            })
+        })?;
+        log::trace!("Get key mapping - END");
+        KeyMapping::from_bytes(&data)
+    }
+
+    /// Write key mapping to the keyboard (experimental)
+    pub fn set_key_mapping(&self, mapping: KeyMapping) -> Result<()> {
+        self.require_experimental("Key remapping")?;
+        log::trace!("Set key mapping - START");
+        let payloads = mapping.get_payloads()?;
+        self.transaction(|| {
+            for payload in &payloads {
+                self.send_payload(payload.clone())?;
+            }
+            Ok(())
+        })?;
+        log::trace!("Set key mapping - END");
+        Ok(())
+    }
+
+    /// Upload macro into `slot` of the keyboard (experimental)
+    pub fn set_macro(&self, slot: u8, sequence: Macro) -> Result<()> {
+        self.require_experimental("Macro upload")?;
+        log::trace!("Set macro - START");
+        let payloads = sequence.get_payloads(slot)?;
+        self.transaction(|| {
+            for payload in &payloads {
+                self.send_payload(payload.clone())?;
+            }
+            Ok(())
+        })?;
+        log::trace!("Set macro - END");
+        Ok(())
+    }
+
+    /// Let key at `key_index` play the macro stored in `slot` (experimental)
+    pub fn assign_macro(&self, key_index: usize, slot: u8) -> Result<()> {
+        self.require_experimental("Macro assignment")?;
+        if key_index >= models::TOTAL_KEYS {
+            return Err(Error::KeyIndexOutOfBounds(key_index));
+        }
+
+        self.transaction(|| {
+            self.send_payload(Payload::AssignMacro {
+                key_index: key_index as u8,
+                slot,
+            })?;
+            Ok(())
+        })
+    }
+
+    /// Switch the active onboard profile (experimental)
+    pub fn set_active_profile(&self, slot: u8) -> Result<()> {
+        self.require_experimental("Profile activation")?;
+        log::trace!("Set active profile - START");
+        self.transaction(|| {
+            self.send_payload(Payload::ActivateProfile { slot })?;
+            Ok(())
+        })?;
+        log::trace!("Set active profile - END");
+        Ok(())
+    }
+
+    /// Query USB polling rate of the keyboard (experimental)
+    pub fn polling_rate(&self) -> Result<UsbPollingRate> {
+        self.require_experimental("Polling rate query")?;
+        let response = self.send_payload_retry(Payload::GetPollingRate)?;
+
+        Ok(std::io::Cursor::new(&response[4..]).read_ne()?)
+    }
+
+    /// Query USB descriptors and firmware version of the keyboard
+    ///
+    /// The firmware version is taken from the device release number (`bcdDevice`).
+    /// With experimental commands enabled, the keyboard is asked for its firmware
+    /// version and layout id as well. If it does not give a plausible answer,
+    /// `layout_id` stays `None`.
+    pub fn device_info(&self) -> Result<DeviceInfo> {
+        let usb = self.transport.identity()?;
+        let mut firmware_version = usb
+            .device_version
+            .as_deref()
+            .and_then(FirmwareVersion::from_device_version);
+        let mut layout_id = None;
+
+        if self.experimental {
+            // Single attempt, a keyboard ignoring the query would cost a timeout per retry
+            match self.send_payload(Payload::GetFirmwareVersion) {
+                // An echo of the request or an empty answer carries no data
+                Ok(response) if response[4..7] == [0, 0, 0] => {
+                    log::warn!("Keyboard did not report firmware version")
+                }
+                Ok(response) => {
+                    firmware_version = Some(FirmwareVersion {
+                        major: response[4],
+                        minor: response[5],
+                    });
+                    layout_id = Some(response[6]);
+                }
+                Err(e) if e.is_transient() => {
+                    log::warn!("Keyboard did not report firmware version: {}", e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(DeviceInfo {
+            usb,
+            firmware_version,
+            layout_id,
+        })
+    }
+
+    /// Set USB polling rate of the keyboard (experimental)
+    pub fn set_polling_rate(&self, rate: UsbPollingRate) -> Result<()> {
+        self.require_experimental("Setting the polling rate")?;
+        log::trace!("Set polling rate - START");
+        self.transaction(|| {
+            self.send_payload(Payload::SetPollingRate { rate: rate.clone() })?;
+            Ok(())
+        })?;
+        log::trace!("Set polling rate - END");
         Ok(())
     }
 
@@ -381,8 +1131,8 @@ impl CherryKeyboard {
         self.set_custom_colors(CustomKeyLeds::new())?;
 
         // Payloads, type: 0x5
-        self.send_payload(Payload::Unknown5 { unk: 0x01 })?;
-        self.send_payload(Payload::Unknown5 { unk: 0x19 })?;
+        self.send_payload_retry(Payload::Unknown5 { unk: 0x01 })?;
+        self.send_payload_retry(Payload::Unknown5 { unk: 0x19 })?;
         log::trace!("Reset custom colors - END");
         Ok(())
     }
@@ -602,6 +1352,122 @@ mod tests {
         }
     }
 
+    fn mock_keyboard() -> CherryKeyboard<MockKeyboard> {
+        CherryKeyboard::from_transport(MockKeyboard::new()).with_retries(0)
+    }
+
+    #[test]
+    fn mock_led_animation() {
+        let keyboard = mock_keyboard();
+        keyboard
+            .set_led_animation(
+                LightingMode::Rain,
+                Brightness::Medium,
+                Speed::Slow,
+                RGB8::new(0, 0xff, 0),
+                false,
+            )
+            .unwrap();
+
+        let payloads: Vec<Payload> = keyboard
+            .transport()
+            .sent_packets()
+            .iter()
+            .map(|pkt| pkt.payload().clone())
+            .collect();
+
+        assert_eq!(
+            payloads,
+            vec![
+                Payload::TransactionStart,
+                Payload::SetAnimation {
+                    unknown: [0x09, 0x00, 0x00, 0x55, 0x00],
+                    mode: LightingMode::Rain,
+                    brightness: Brightness::Medium,
+                    speed: Speed::Slow,
+                    pad: 0x0,
+                    rainbow: 0,
+                    color: OwnRGB8::new(0, 0xff, 0),
+                },
+                Payload::SetAnimation {
+                    unknown: [0x01, 0x18, 0x00, 0x55, 0x01],
+                    mode: LightingMode::Wave,
+                    brightness: Brightness::Off,
+                    speed: Speed::VeryFast,
+                    pad: 0x0,
+                    rainbow: 0x0,
+                    color: OwnRGB8::new(0, 0, 0),
+                },
+                Payload::TransactionEnd,
+            ]
+        );
+    }
+
+    #[test]
+    fn mock_custom_colors() {
+        let keyboard = mock_keyboard();
+        let mut keys = CustomKeyLeds::new();
+        keys.set_led(42, RGB8::new(0x11, 0x22, 0x33)).unwrap();
+
+        keyboard.set_custom_colors(keys).unwrap();
+
+        let packets = keyboard.transport().sent_packets();
+        // 4 packets for setting custom mode + 7 chunks of key colors
+        assert_eq!(packets.len(), 11);
+        assert_eq!(packets[1].payload().payload_type(), 0x06);
+        assert_eq!(packets[3].payload(), &Payload::TransactionEnd);
+
+        let mut key_data = Vec::new();
+        for (index, pkt) in packets[4..].iter().enumerate() {
+            assert!(pkt.verify_checksum().is_ok());
+            match pkt.payload() {
+                Payload::SetCustomLED {
+                    data_offset,
+                    key_leds_data,
+                    ..
+                } => {
+                    assert_eq!(*data_offset as usize, index * 56);
+                    key_data.extend_from_slice(key_leds_data);
+                }
+                other => panic!("Unexpected payload {:?}", other),
+            }
+        }
+        assert_eq!(key_data.len(), 126 * 3);
+        assert_eq!(key_data[42 * 3..43 * 3], [0x11, 0x22, 0x33]);
+        assert!(key_data[..42 * 3].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn mock_reset_custom_colors() {
+        let keyboard = mock_keyboard();
+        keyboard.reset_custom_colors().unwrap();
+
+        let packets = keyboard.transport().sent_packets();
+        assert_eq!(packets.len(), 13);
+        assert_eq!(packets[11].payload(), &Payload::Unknown5 { unk: 0x01 });
+        assert_eq!(packets[12].payload(), &Payload::Unknown5 { unk: 0x19 });
+    }
+
+    #[test]
+    fn mock_fetch_device_state() {
+        let keyboard = mock_keyboard();
+        keyboard.fetch_device_state().unwrap();
+
+        // Must match the captured fetch device info packets byte by byte
+        let expected: Vec<Vec<u8>> = std::iter::once("04 01 00 01")
+            .chain(packets()[22..33].iter().copied())
+            .chain(std::iter::once("04 02 00 02"))
+            .map(|pkt_str| hex::decode(pkt_str.replace(' ', "")).unwrap())
+            .collect();
+
+        let reports = keyboard.transport().sent_reports();
+        assert_eq!(reports.len(), expected.len());
+        for (report, expected) in reports.iter().zip(expected) {
+            assert_eq!(report[..expected.len()], expected[..]);
+            assert!(report[expected.len()..].iter().all(|&b| b == 0));
+        }
+    }
+
     #[test]
     fn deserialize_color_profile() {
         let color_profile = r#"
@@ -612,6 +1478,7 @@ mod tests {
             }
         "#;
 
+        let layout = Layout::FULL_SIZE_ANSI;
         let match_this: Vec<ProfileKey> = vec![
             ProfileKey::new(0, OwnRGB8::new(255, 0, 0)),
             ProfileKey::new(1, OwnRGB8::new(0, 255, 0)),
@@ -620,5 +1487,641 @@ mod tests {
 
         let profile_keys = read_color_profile(color_profile).expect("Failed reading color profile");
         assert_eq!(match_this, profile_keys);
+
+        let profile_keys = read_color_profile(r#"{ "esc": "ff0000", "W": "00ff00" }"#).unwrap();
+        let mut keys = CustomKeyLeds::new();
+        keys.set_key(&layout, "esc", RGB8::new(255, 0, 0)).unwrap();
+        keys.set_key(&layout, "w", RGB8::new(0, 255, 0)).unwrap();
+        assert_eq!(CustomKeyLeds::try_from(profile_keys).unwrap(), keys);
+        assert_eq!(keys.get_led(0), Some(&OwnRGB8::new(255, 0, 0)));
+
+        assert!(matches!(
+            keys.set_key(&layout, "nope", RGB8::new(0, 0, 0)),
+            Err(Error::UnknownKey(_))
+        ));
+        assert!(matches!(
+            read_color_profile(r#"{ "nope": "ff0000" }"#),
+            Err(Error::InvalidProfile(_))
+        ));
+
+        // Names are resolved in the layout of the keyboard, a TKL has no numpad
+        assert!(read_color_profile(r#"{ "numpad_enter": "ff0000" }"#).is_ok());
+        assert!(matches!(
+            read_color_profile_with_layout(
+                r#"{ "numpad_enter": "ff0000" }"#,
+                &Layout::TENKEYLESS_ANSI
+            ),
+            Err(Error::InvalidProfile(_))
+        ));
+        assert!(matches!(
+            keys.set_key(&Layout::TENKEYLESS_ANSI, "numpad_enter", RGB8::new(0, 0, 0)),
+            Err(Error::UnknownKey(_))
+        ));
+
+        // Digit keys are named, plain numbers stay key indices
+        let profile_keys = read_color_profile(r#"{ "digit_1": "ff0000", "1": "00ff00" }"#).unwrap();
+        let keys = CustomKeyLeds::try_from(profile_keys).unwrap();
+        assert_eq!(
+            keys.get_led(layout.key_index("digit_1").unwrap()),
+            Some(&OwnRGB8::new(255, 0, 0))
+        );
+        assert_eq!(keys.get_led(1), Some(&OwnRGB8::new(0, 255, 0)));
+    }
+
+    #[test]
+    fn typed_errors() {
+        let mut keys = CustomKeyLeds::new();
+        assert!(matches!(
+            keys.set_led(126, RGB8::new(0, 0, 0)),
+            Err(Error::KeyIndexOutOfBounds(126))
+        ));
+        assert!(matches!(
+            read_color_profile("{ invalid"),
+            Err(Error::ProfileJson(_))
+        ));
+        assert!(matches!(
+            read_color_profile(r#"{ "0": "nope" }"#),
+            Err(Error::InvalidProfile(_))
+        ));
+        assert!(matches!(
+            read_macro_file("[ invalid"),
+            Err(Error::InvalidMacro(_))
+        ));
+        assert!(matches!(Error::from(rusb::Error::Timeout), Error::Timeout));
+    }
+
+    #[test]
+    fn mock_rejected_command() {
+        let keyboard = mock_keyboard();
+        // Keyboard answers TransactionStart with TransactionEnd
+        let mut response = [0u8; REPORT_SIZE];
+        response[..4].copy_from_slice(&[0x04, 0x02, 0x00, 0x02]);
+        keyboard.transport().inject_response(response);
+
+        assert!(matches!(
+            keyboard.fetch_device_state(),
+            Err(Error::UnexpectedResponse {
+                expected: 0x01,
+                got: 0x02
+            })
+        ));
+    }
+
+    #[test]
+    fn mock_device_info() {
+        let keyboard = mock_keyboard();
+        let info = keyboard.device_info().unwrap();
+
+        // Taken from the USB descriptor without sending anything
+        assert!(keyboard.transport().sent_reports().is_empty());
+        assert_eq!(info.usb.vendor_id, CHERRY_USB_VID);
+        assert_eq!(info.usb.manufacturer.as_deref(), Some("Cherry GmbH"));
+        assert_eq!(
+            info.firmware_version,
+            Some(FirmwareVersion { major: 1, minor: 2 })
+        );
+        assert_eq!(info.firmware_version.unwrap().to_string(), "1.02");
+        assert_eq!(info.layout_id, None);
+
+        let keyboard = keyboard.with_experimental(true);
+        let info = keyboard.device_info().unwrap();
+        assert_eq!(info.layout_id, Some(0));
+
+        // Keyboard echoing or rejecting the firmware query still yields the USB identity
+        let mut echo = [0u8; REPORT_SIZE];
+        echo[..4].copy_from_slice(&[0x04, 0x0e, 0x00, 0x0e]);
+        let mut rejected = [0u8; REPORT_SIZE];
+        rejected[..4].copy_from_slice(&[0x04, 0x02, 0x00, 0x02]);
+        for response in [echo, rejected] {
+            keyboard.transport().inject_response(response);
+
+            let info = keyboard.device_info().unwrap();
+            assert_eq!(info.usb.product_id, 0x00dd);
+            assert_eq!(
+                info.firmware_version,
+                Some(FirmwareVersion { major: 1, minor: 2 })
+            );
+            assert_eq!(info.layout_id, None);
+        }
+    }
+
+    #[test]
+    fn mock_refuses_color_without_rgb() {
+        let keyboard = mock_keyboard().with_model(DeviceModel::from_product_id(0x00c3));
+
+        assert!(matches!(
+            keyboard.set_custom_colors(CustomKeyLeds::new()),
+            Err(Error::UnsupportedFeature {
+                model: "G80 3000 TKL NBL",
+                ..
+            })
+        ));
+        assert!(keyboard.transport().sent_reports().is_empty());
+    }
+
+    #[test]
+    fn mock_apply_lighting() {
+        let keyboard = mock_keyboard();
+        let mut keys = CustomKeyLeds::new();
+        keys.set_led(3, RGB8::new(0x11, 0x22, 0x33)).unwrap();
+
+        keyboard
+            .apply_lighting(&LightingConfig::Animation {
+                mode: LightingMode::Breathing,
+                brightness: Brightness::High,
+                speed: Speed::Fast,
+                color: RGB8::new(0xff, 0, 0).into(),
+                rainbow: false,
+            })
+            .unwrap();
+        let state = keyboard.fetch_device_state().unwrap();
+        assert_eq!(state.mode, Some(LightingMode::Breathing));
+        assert_eq!(state.color, Some(RGB8::new(0xff, 0, 0).into()));
+
+        keyboard
+            .apply_lighting(&LightingConfig::CustomColors(keys.clone()))
+            .unwrap();
+        assert_eq!(
+            keyboard.get_custom_colors().unwrap().get_led(3),
+            keys.get_led(3)
+        );
+    }
+
+    #[test]
+    fn mock_set_brightness() {
+        let keyboard = mock_keyboard();
+        let animation = LightingConfig::Animation {
+            mode: LightingMode::Wave,
+            brightness: Brightness::Full,
+            speed: Speed::Fast,
+            color: RGB8::new(0, 0, 0xff).into(),
+            rainbow: false,
+        };
+
+        keyboard
+            .set_brightness(Brightness::Low, &animation)
+            .unwrap();
+
+        let state = keyboard.fetch_device_state().unwrap();
+        assert_eq!(state.brightness, Some(Brightness::Low));
+        assert_eq!(state.mode, Some(LightingMode::Wave));
+        assert_eq!(state.speed, Some(Speed::Fast));
+        assert_eq!(state.color, Some(RGB8::new(0, 0, 0xff).into()));
+
+        keyboard
+            .set_brightness(
+                Brightness::Medium,
+                &LightingConfig::CustomColors(CustomKeyLeds::new()),
+            )
+            .unwrap();
+
+        let state = keyboard.fetch_device_state().unwrap();
+        assert_eq!(state.brightness, Some(Brightness::Medium));
+        assert_eq!(state.mode, Some(LightingMode::Custom));
+    }
+
+    #[test]
+    fn mock_play_effect() {
+        struct Frames(usize);
+        impl Effect for Frames {
+            fn render(&mut self, _elapsed: Duration) -> CustomKeyLeds {
+                self.0 += 1;
+                let mut keys = CustomKeyLeds::new();
+                keys.set_led(self.0, RGB8::new(0, 0xff, 0)).unwrap();
+                keys
+            }
+        }
+
+        let keyboard = mock_keyboard();
+        let mut effect = Frames(0);
+
+        // Zero duration renders exactly one frame
+        keyboard
+            .play_effect(
+                &mut effect,
+                Brightness::Full,
+                30,
+                Some(Duration::ZERO),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+
+        assert_eq!(effect.0, 1);
+        assert_eq!(keyboard.transport().sent_packets().len(), 11);
+        assert_eq!(
+            keyboard.get_custom_colors().unwrap().get_led(1),
+            Some(&RGB8::new(0, 0xff, 0).into())
+        );
+
+        // So does stopping right away
+        keyboard
+            .play_effect(
+                &mut effect,
+                Brightness::Full,
+                30,
+                None,
+                &AtomicBool::new(true),
+            )
+            .unwrap();
+        assert_eq!(effect.0, 2);
+    }
+
+    #[test]
+    fn mock_stream_custom_colors() {
+        let keyboard = mock_keyboard();
+        let mut stream = keyboard.stream_custom_colors(Brightness::Low).unwrap();
+        assert!(matches!(
+            keyboard.transport().sent_packets()[1].payload(),
+            Payload::SetAnimation {
+                mode: LightingMode::Custom,
+                brightness: Brightness::Low,
+                ..
+            }
+        ));
+        keyboard.transport().clear();
+
+        let mut keys = CustomKeyLeds::new();
+        keys.set_led(3, RGB8::new(0xff, 0, 0)).unwrap();
+        assert_eq!(stream.send_frame(keys.clone()).unwrap(), 7);
+
+        // Same frame again sends nothing
+        keyboard.transport().clear();
+        assert_eq!(stream.send_frame(keys.clone()).unwrap(), 0);
+
+        // Key 100 (bytes 300..303) is in the chunk at offset 280
+        keys.set_led(100, RGB8::new(0, 0, 0xff)).unwrap();
+        assert_eq!(stream.send_frame(keys.clone()).unwrap(), 1);
+        let packets = keyboard.transport().sent_packets();
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(
+            packets[0].payload(),
+            Payload::SetCustomLED {
+                data_offset: 280,
+                ..
+            }
+        ));
+        assert_eq!(keyboard.get_custom_colors().unwrap(), keys);
+
+        stream.invalidate();
+        assert_eq!(stream.send_frame(keys).unwrap(), 7);
+    }
+
+    #[test]
+    fn mock_play_reactive_effect() {
+        // Press and release A, as read from /dev/input/eventN
+        let mut events = Vec::new();
+        for value in [1i32, 0] {
+            events.extend(vec![0u8; INPUT_EVENT_SIZE - 8]);
+            events.extend(1u16.to_ne_bytes());
+            events.extend(30u16.to_ne_bytes());
+            events.extend(value.to_ne_bytes());
+        }
+
+        let keyboard = mock_keyboard();
+        let mut effect = TypingHeatmap::new();
+        keyboard
+            .play_reactive_effect(
+                &mut effect,
+                vec![KeyEventReader::new(std::io::Cursor::new(events))],
+                Brightness::Full,
+                100,
+                Some(Duration::from_millis(100)),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+
+        let key_index = Layout::default().key_index("a").unwrap();
+        assert_eq!(effect.key_press_counts().counts()[key_index], 1);
+        assert_eq!(
+            keyboard.get_custom_colors().unwrap().get_led(key_index),
+            Some(&RGB8::new(0xff, 0, 0).into())
+        );
+    }
+
+    #[test]
+    fn mock_garbled_response() {
+        let keyboard = mock_keyboard();
+        let mut response = [0u8; REPORT_SIZE];
+        response[..4].copy_from_slice(&[0x04, 0x01, 0x00, 0x01]);
+        response[20] = 0x42;
+        keyboard.transport().inject_response(response);
+
+        assert!(matches!(
+            keyboard.reset_custom_colors(),
+            Err(Error::ChecksumMismatch {
+                expected: 0x43,
+                got: 0x01
+            })
+        ));
+    }
+
+    #[test]
+    fn mock_bad_magic() {
+        let keyboard = mock_keyboard();
+        let mut response = [0u8; REPORT_SIZE];
+        response[..4].copy_from_slice(&[0x05, 0x01, 0x00, 0x01]);
+        keyboard.transport().inject_response(response);
+
+        let err = keyboard.reset_custom_colors().unwrap_err();
+        assert!(err.is_transient());
+        assert!(matches!(err, Error::BadMagic { got: 0x05 }));
+    }
+
+    #[test]
+    fn retry_backoff_capped() {
+        let keyboard = mock_keyboard().with_backoff(Duration::from_millis(100));
+
+        assert_eq!(keyboard.retry_delay(0), Duration::from_millis(100));
+        assert_eq!(keyboard.retry_delay(2), Duration::from_millis(400));
+        assert_eq!(keyboard.retry_delay(10), MAX_BACKOFF);
+        assert_eq!(keyboard.retry_delay(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn mock_retry_restarts_transaction() {
+        let keyboard = CherryKeyboard::from_transport(MockKeyboard::new())
+            .with_retries(1)
+            .with_backoff(Duration::ZERO);
+        // Acknowledge TransactionStart, then answer SetAnimation with the wrong payload type
+        let mut response = [0u8; REPORT_SIZE];
+        response[..4].copy_from_slice(&[0x04, 0x01, 0x00, 0x01]);
+        keyboard.transport().inject_response(response);
+        keyboard.transport().inject_response(response);
+
+        keyboard
+            .set_led_animation(
+                LightingMode::Wave,
+                Brightness::Full,
+                Speed::Fast,
+                RGB8::new(0, 0, 0),
+                false,
+            )
+            .unwrap();
+
+        let types: Vec<u8> = keyboard
+            .transport()
+            .sent_packets()
+            .iter()
+            .map(|pkt| pkt.payload().payload_type())
+            .collect();
+        // First attempt fails on SetAnimation and is closed, second one runs to completion
+        assert_eq!(types, vec![0x01, 0x06, 0x02, 0x01, 0x06, 0x06, 0x02]);
+    }
+
+    #[test]
+    fn mock_decode_device_state() {
+        let keyboard = mock_keyboard();
+        keyboard
+            .set_led_animation(
+                LightingMode::Ripples,
+                Brightness::Low,
+                Speed::VerySlow,
+                RGB8::new(0x12, 0x34, 0x56),
+                true,
+            )
+            .unwrap();
+
+        let state = keyboard.fetch_device_state().unwrap();
+        assert_eq!(state.mode, Some(LightingMode::Ripples));
+        assert_eq!(state.brightness, Some(Brightness::Low));
+        assert_eq!(state.speed, Some(Speed::VerySlow));
+        assert_eq!(state.color, Some(OwnRGB8::new(0x12, 0x34, 0x56)));
+        assert_eq!(state.rainbow, Some(true));
+        assert_eq!(state.settings.len(), DeviceState::SETTINGS_SIZE);
+        assert_eq!(
+            state.unknown7,
+            (0..0x17A).map(|i| i as u8).collect::<Vec<u8>>()
+        );
+        assert_eq!(
+            state.unknown1b,
+            (0..0x7E).map(|i| i as u8).collect::<Vec<u8>>()
+        );
+    }
+
+    #[test]
+    fn decode_unknown_mode() {
+        let state = DeviceState::decode(vec![0x00, 0x42, 0x07], vec![], vec![]);
+        assert_eq!(state.mode, None);
+        assert_eq!(state.brightness, None);
+        assert_eq!(state.color, None);
+    }
+
+    #[test]
+    fn mock_get_custom_colors() {
+        let keyboard = mock_keyboard();
+        let mut keys = CustomKeyLeds::new();
+        keys.set_led(0, RGB8::new(0xff, 0, 0)).unwrap();
+        keys.set_led(125, RGB8::new(0, 0, 0xff)).unwrap();
+
+        keyboard.set_custom_colors(keys.clone()).unwrap();
+        keyboard.transport().clear();
+
+        assert_eq!(keyboard.get_custom_colors().unwrap(), keys);
+        // Start, 7 reads, end
+        assert_eq!(keyboard.transport().sent_packets().len(), 9);
+    }
+
+    #[test]
+    fn mock_key_mapping() {
+        // Every key emits 'a' (0x04)
+        let table = vec![0x04; DeviceState::UNKNOWN1B_SIZE];
+        let keyboard = CherryKeyboard::from_transport(MockKeyboard::with_state(Vec::new(), table))
+            .with_retries(0);
+        let mut mapping = keyboard.get_key_mapping().unwrap();
+        assert_eq!(mapping.get(0), Some(0x04));
+
+        // Caps Lock -> Left Ctrl
+        let caps_lock = Layout::default().key_index("caps_lock").unwrap();
+        mapping.set(caps_lock, 0xE0).unwrap();
+        assert!(matches!(
+            mapping.set(126, 0xE0),
+            Err(Error::KeyIndexOutOfBounds(126))
+        ));
+
+        // Refused without touching the keyboard
+        keyboard.transport().clear();
+        assert!(matches!(
+            keyboard.set_key_mapping(mapping.clone()),
+            Err(Error::Experimental(_))
+        ));
+        assert!(keyboard.transport().sent_reports().is_empty());
+
+        let keyboard = keyboard.with_experimental(true);
+        keyboard.set_key_mapping(mapping).unwrap();
+
+        // The changed key ends up at its index in the chunked table
+        let written: Vec<u8> = keyboard
+            .transport()
+            .sent_packets()
+            .iter()
+            .filter_map(|pkt| match pkt.payload() {
+                Payload::SetKeyMapping { key_map_data, .. } => Some(key_map_data.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(written.len(), DeviceState::UNKNOWN1B_SIZE);
+        assert_eq!(written[caps_lock], 0xE0);
+        assert_eq!(
+            written.iter().filter(|&&usage| usage == 0x04).count(),
+            written.len() - 1
+        );
+    }
+
+    #[test]
+    fn deserialize_macro_file() {
+        let macro_file = r#"
+            [
+                {
+                    "key": 117,
+                    "events": [
+                        { "action": "press", "usage": 11, "delay": 20 },
+                        { "action": "release", "usage": 11, "delay": 300 }
+                    ]
+                }
+            ]
+        "#;
+
+        let macros = read_macro_file(macro_file).expect("Failed reading macro file");
+        assert_eq!(macros.len(), 1);
+        assert_eq!(macros[0].key_index, 117);
+        assert_eq!(Layout::default().key_name(117), Some("menu"));
+        assert_eq!(
+            macros[0].sequence.events(),
+            &[
+                MacroEvent {
+                    action: MacroAction::Press,
+                    usage: 11,
+                    delay_ms: 20
+                },
+                MacroEvent {
+                    action: MacroAction::Release,
+                    usage: 11,
+                    delay_ms: 300
+                }
+            ]
+        );
+
+        assert!(matches!(
+            read_macro_file(r#"[{ "key": 1, "events": [{ "action": "tap" }] }]"#),
+            Err(Error::InvalidMacro(_))
+        ));
+        assert!(matches!(
+            read_macro_file(r#"[{ "key": 126, "events": [] }]"#),
+            Err(Error::KeyIndexOutOfBounds(126))
+        ));
+    }
+
+    #[test]
+    fn mock_set_macro() {
+        let keyboard = mock_keyboard();
+        let events: Vec<MacroEvent> = (0..20)
+            .map(|i| MacroEvent {
+                action: MacroAction::Press,
+                usage: i,
+                delay_ms: 0x0102,
+            })
+            .collect();
+
+        assert!(matches!(
+            keyboard.set_macro(2, Macro::new(events.clone()).unwrap()),
+            Err(Error::Experimental(_))
+        ));
+        assert!(matches!(
+            keyboard.assign_macro(117, 2),
+            Err(Error::Experimental(_))
+        ));
+        assert!(keyboard.transport().sent_reports().is_empty());
+
+        let keyboard = keyboard.with_experimental(true);
+        keyboard.set_macro(2, Macro::new(events).unwrap()).unwrap();
+        keyboard.assign_macro(117, 2).unwrap();
+
+        let payloads: Vec<Payload> = keyboard
+            .transport()
+            .sent_packets()
+            .iter()
+            .map(|pkt| pkt.payload().clone())
+            .collect();
+        // 1 + 20 * 4 bytes split into two chunks
+        match (&payloads[1], &payloads[2]) {
+            (
+                Payload::SetMacro {
+                    data_offset: 0,
+                    slot: 2,
+                    macro_data: first,
+                },
+                Payload::SetMacro {
+                    data_offset: 56,
+                    slot: 2,
+                    macro_data: second,
+                },
+            ) => {
+                assert_eq!(first[..5], [20, 0x01, 0x00, 0x02, 0x01]);
+                assert_eq!(second.len(), 25);
+            }
+            other => panic!("Unexpected payloads {:?}", other),
+        }
+        assert_eq!(
+            payloads[5],
+            Payload::AssignMacro {
+                key_index: 117,
+                slot: 2
+            }
+        );
+    }
+
+    #[test]
+    fn mock_polling_rate() {
+        let keyboard = mock_keyboard();
+        assert!(matches!(
+            keyboard.set_polling_rate(UsbPollingRate::Medium),
+            Err(Error::Experimental(_))
+        ));
+        assert!(keyboard.transport().sent_reports().is_empty());
+
+        let keyboard = keyboard.with_experimental(true);
+        assert_eq!(keyboard.polling_rate().unwrap(), UsbPollingRate::Full);
+
+        keyboard.set_polling_rate(UsbPollingRate::Medium).unwrap();
+        assert_eq!(keyboard.polling_rate().unwrap(), UsbPollingRate::Medium);
+        assert_eq!(UsbPollingRate::from_str("high"), Ok(UsbPollingRate::High));
+        assert_eq!(UsbPollingRate::High.hz(), 500);
+    }
+
+    #[test]
+    fn mock_profile_slots() {
+        let keyboard = mock_keyboard().with_profile(Some(2));
+        assert!(matches!(
+            keyboard.set_custom_colors(CustomKeyLeds::new()),
+            Err(Error::Experimental(_))
+        ));
+        assert!(keyboard.transport().sent_reports().is_empty());
+
+        let keyboard = keyboard.with_experimental(true);
+        keyboard.set_custom_colors(CustomKeyLeds::new()).unwrap();
+        keyboard.set_active_profile(2).unwrap();
+
+        let payloads: Vec<Payload> = keyboard
+            .transport()
+            .sent_packets()
+            .iter()
+            .map(|pkt| pkt.payload().clone())
+            .collect();
+        assert_eq!(payloads[0], Payload::TransactionStart);
+        match &payloads[1] {
+            Payload::SetAnimation { unknown, .. } => {
+                assert_eq!(unknown, &[0x09, 0x00, 0x00, 0x55, 0x02])
+            }
+            other => panic!("Unexpected payload {:?}", other),
+        }
+        assert_eq!(
+            payloads[payloads.len() - 3..],
+            [
+                Payload::TransactionStart,
+                Payload::ActivateProfile { slot: 2 },
+                Payload::TransactionEnd
+            ]
+        );
     }
 }
@@ -0,0 +1,292 @@
+use crate::{
+    calc_checksum,
+    models::{DeviceState, Packet, Payload, UsbIdentity, UsbPollingRate},
+    transport::{Report, Transport, REPORT_SIZE},
+    Error, Result, CHERRY_USB_VID, READ_DATA_OFFSET,
+};
+use binrw::BinReaderExt;
+use std::{collections::VecDeque, io::Cursor, sync::Mutex, time::Duration};
+
+/// Settings block served for `Unknown3` reads: static white, medium speed, full brightness
+const DEFAULT_SETTINGS: [u8; 9] = [0x00, 0x03, 0x04, 0x02, 0x00, 0x00, 0xFF, 0xFF, 0xFF];
+/// Answer to `GetFirmwareVersion`: version 1.02, layout 0
+const FIRMWARE_VERSION: [u8; 3] = [0x01, 0x02, 0x00];
+
+#[derive(Default)]
+struct MockState {
+    received: Vec<Report>,
+    responses: VecDeque<Report>,
+    injected: VecDeque<Report>,
+    settings: Vec<u8>,
+    polling_rate: u8,
+    unknown7_state: Vec<u8>,
+    unknown1b_state: Vec<u8>,
+}
+
+/// In-memory stand-in for a Cherry keyboard
+///
+/// Records every report sent to it and answers each one with a plausible
+/// interrupt response, so `CherryKeyboard` can be exercised without hardware.
+pub struct MockKeyboard {
+    state: Mutex<MockState>,
+}
+
+impl Default for MockKeyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockKeyboard {
+    /// Create mock keyboard with canned state filled with an incrementing byte pattern
+    pub fn new() -> Self {
+        Self::with_state(
+            (0..DeviceState::UNKNOWN7_SIZE).map(|i| i as u8).collect(),
+            (0..DeviceState::UNKNOWN1B_SIZE).map(|i| i as u8).collect(),
+        )
+    }
+
+    /// Create mock keyboard serving the provided data for `Unknown7` / `Unknown1B` reads
+    ///
+    /// `SetCustomLED` / `SetKeyMapping` writes end up in `unknown7_state` / `unknown1b_state`.
+    pub fn with_state(unknown7_state: Vec<u8>, unknown1b_state: Vec<u8>) -> Self {
+        let mut settings = DEFAULT_SETTINGS.to_vec();
+        settings.resize(DeviceState::SETTINGS_SIZE, 0x00);
+
+        Self {
+            state: Mutex::new(MockState {
+                settings,
+                polling_rate: UsbPollingRate::Full as u8,
+                unknown7_state,
+                unknown1b_state,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Raw reports received so far, in order
+    pub fn sent_reports(&self) -> Vec<Report> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Received reports parsed into packets, in order
+    pub fn sent_packets(&self) -> Vec<Packet<Payload>> {
+        self.sent_reports()
+            .iter()
+            .map(|report| {
+                Cursor::new(report)
+                    .read_ne::<Packet<Payload>>()
+                    .expect("Mock received unparseable report")
+            })
+            .collect()
+    }
+
+    /// Answer the next report with `response` instead of the computed one,
+    /// e.g. to simulate a garbled or rejected command
+    pub fn inject_response(&self, response: Report) {
+        self.state.lock().unwrap().injected.push_back(response);
+    }
+
+    /// Forget all received reports, pending and injected responses
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.received.clear();
+        state.responses.clear();
+        state.injected.clear();
+    }
+
+    /// Build a read response: echo the request header and append the requested data
+    fn read_response(request: &Report, data_len: u8, data_offset: u16, source: &[u8]) -> Report {
+        let mut response = [0u8; REPORT_SIZE];
+        response[0] = request[0];
+        response[3] = request[3];
+        response[4] = data_len;
+        response[5..7].copy_from_slice(&data_offset.to_le_bytes());
+
+        let start = (data_offset as usize).min(source.len());
+        let end = (start + data_len as usize)
+            .min(source.len())
+            .min(start + REPORT_SIZE - READ_DATA_OFFSET);
+        let data = &source[start..end];
+        response[READ_DATA_OFFSET..READ_DATA_OFFSET + data.len()].copy_from_slice(data);
+
+        let checksum = calc_checksum(response[3], &response[4..]);
+        response[1..3].copy_from_slice(&checksum.to_le_bytes());
+
+        response
+    }
+
+    /// Build a query response: echo the request with `data` as payload
+    fn query_response(request: &Report, data: &[u8]) -> Report {
+        let mut response = *request;
+        response[4..4 + data.len()].copy_from_slice(data);
+        let checksum = calc_checksum(response[3], &response[4..]);
+        response[1..3].copy_from_slice(&checksum.to_le_bytes());
+
+        response
+    }
+
+    /// Current settings block, as modified by `SetAnimation` writes
+    pub fn settings(&self) -> Vec<u8> {
+        self.state.lock().unwrap().settings.clone()
+    }
+
+    /// Store `data` at `offset` of `target`, growing it if required
+    fn write_data(target: &mut Vec<u8>, offset: usize, data: &[u8]) {
+        if target.len() < offset + data.len() {
+            target.resize(offset + data.len(), 0);
+        }
+        target[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    /// Compute the response the keyboard would send for `request`, updating state for writes
+    fn respond(state: &mut MockState, request: &Report) -> Report {
+        let packet = match Cursor::new(request).read_ne::<Packet<Payload>>() {
+            Ok(packet) => packet,
+            // Garbage in, garbage out
+            Err(_) => return *request,
+        };
+
+        match *packet.payload() {
+            Payload::Unknown3 { unk } => Self::read_response(request, unk, 0, &state.settings),
+            Payload::SetAnimation { unknown, .. } => {
+                // unknown[0] bytes at offset unknown[1..3], data starts at unknown[4]
+                let data_offset = u16::from_le_bytes([unknown[1], unknown[2]]) as usize;
+                let end = (READ_DATA_OFFSET + unknown[0] as usize).min(REPORT_SIZE);
+                Self::write_data(
+                    &mut state.settings,
+                    data_offset,
+                    &request[READ_DATA_OFFSET..end],
+                );
+                *request
+            }
+            Payload::SetCustomLED {
+                data_offset,
+                ref key_leds_data,
+                ..
+            } => {
+                Self::write_data(
+                    &mut state.unknown7_state,
+                    data_offset as usize,
+                    key_leds_data,
+                );
+                *request
+            }
+            Payload::SetKeyMapping {
+                data_offset,
+                ref key_map_data,
+                ..
+            } => {
+                Self::write_data(
+                    &mut state.unknown1b_state,
+                    data_offset as usize,
+                    key_map_data,
+                );
+                *request
+            }
+            Payload::GetPollingRate => Self::query_response(request, &[state.polling_rate]),
+            Payload::GetFirmwareVersion => Self::query_response(request, &FIRMWARE_VERSION),
+            Payload::SetPollingRate { ref rate } => {
+                state.polling_rate = rate.clone() as u8;
+                *request
+            }
+            Payload::Unknown7 {
+                data_len,
+                data_offset,
+            } => Self::read_response(request, data_len, data_offset, &state.unknown7_state),
+            Payload::Unknown1B {
+                data_len,
+                data_offset,
+            } => Self::read_response(
+                request,
+                data_len,
+                data_offset as u16,
+                &state.unknown1b_state,
+            ),
+            // Everything else is acknowledged by echoing the request
+            _ => *request,
+        }
+    }
+}
+
+impl Transport for MockKeyboard {
+    fn send_report(&self, report: &Report, _timeout: Duration) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let response = match state.injected.pop_front() {
+            Some(response) => response,
+            None => Self::respond(&mut state, report),
+        };
+
+        state.received.push(*report);
+        state.responses.push_back(response);
+        Ok(())
+    }
+
+    fn recv_report(&self, _timeout: Duration) -> Result<Report> {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .pop_front()
+            // A real keyboard would not answer either
+            .ok_or(Error::Timeout)
+    }
+
+    fn identity(&self) -> Result<UsbIdentity> {
+        Ok(UsbIdentity {
+            vendor_id: CHERRY_USB_VID,
+            product_id: 0x00dd,
+            device_version: Some("1.02".to_owned()),
+            manufacturer: Some("Cherry GmbH".to_owned()),
+            product: Some("CHERRY Keyboard (mock)".to_owned()),
+            serial_number: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::ToVec;
+
+    fn to_report(payload: Payload) -> Report {
+        let mut report = [0u8; REPORT_SIZE];
+        let bytes = Packet::new(payload).to_vec();
+        report[..bytes.len()].copy_from_slice(&bytes);
+        report
+    }
+
+    #[test]
+    fn echoes_writes() {
+        let mock = MockKeyboard::new();
+        let request = to_report(Payload::TransactionStart);
+
+        mock.send_report(&request, Duration::ZERO).unwrap();
+        assert_eq!(mock.recv_report(Duration::ZERO).unwrap(), request);
+        assert!(mock.recv_report(Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn serves_canned_reads() {
+        let mock = MockKeyboard::new();
+        mock.send_report(
+            &to_report(Payload::Unknown7 {
+                data_len: 0x2A,
+                data_offset: 0x150,
+            }),
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        let response = mock.recv_report(Duration::ZERO).unwrap();
+        let mut cursor = Cursor::new(&response[1..]);
+        let checksum: u16 = cursor.read_ne().unwrap();
+
+        assert_eq!(response[3], 0x07);
+        assert_eq!(checksum, calc_checksum(response[3], &response[4..]));
+        assert_eq!(
+            response[READ_DATA_OFFSET..READ_DATA_OFFSET + 0x2A],
+            (0x150..0x17A).map(|i| i as u8).collect::<Vec<u8>>()[..]
+        );
+    }
+}
@@ -1,18 +1,19 @@
 use crate::{
     calc_checksum,
     extensions::{OwnRGB8, ToVec},
+    layout::Layout,
+    Error, Result,
 };
-use anyhow::{anyhow, Result};
-use binrw::{binrw, until_eof, BinRead, BinWrite, BinWriterExt};
-use std::convert::TryFrom;
-use strum_macros::{EnumString, EnumVariantNames};
+use binrw::{binrw, until_eof, BinRead, BinReaderExt, BinWrite, BinWriterExt};
+use std::{convert::TryFrom, io::Cursor};
+use strum_macros::{AsRefStr, EnumString, EnumVariantNames};
 
 /// Modes support:
 /// -> C: Color
 /// -> S: Speed
 #[binrw]
 #[brw(repr = u8)]
-#[derive(Clone, Eq, PartialEq, Debug, EnumString, EnumVariantNames)]
+#[derive(Clone, Eq, PartialEq, Debug, AsRefStr, EnumString, EnumVariantNames)]
 #[strum(serialize_all = "snake_case")]
 pub enum LightingMode {
     Wave = 0x00,      // CS
@@ -34,11 +35,11 @@ pub enum LightingMode {
     SingleKey = 0x15, // CS
 }
 
-/// Probably controlled at OS / driver level
-/// Just defined here for completeness' sake
+/// USB polling rate of the keyboard
 #[binrw]
 #[brw(repr = u8)]
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, Eq, PartialEq, Debug, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "snake_case")]
 pub enum UsbPollingRate {
     Low,    // 125Hz
     Medium, // 250 Hz
@@ -46,10 +47,22 @@ pub enum UsbPollingRate {
     Full,   // 1000 Hz
 }
 
+impl UsbPollingRate {
+    /// Polling rate in Hz
+    pub fn hz(&self) -> u16 {
+        match self {
+            UsbPollingRate::Low => 125,
+            UsbPollingRate::Medium => 250,
+            UsbPollingRate::High => 500,
+            UsbPollingRate::Full => 1000,
+        }
+    }
+}
+
 /// LED animation speed
 #[binrw]
 #[brw(repr = u8)]
-#[derive(Clone, Eq, PartialEq, Debug, EnumString, EnumVariantNames)]
+#[derive(Clone, Eq, PartialEq, Debug, AsRefStr, EnumString, EnumVariantNames)]
 #[strum(serialize_all = "snake_case")]
 pub enum Speed {
     VeryFast = 0,
@@ -62,7 +75,7 @@ pub enum Speed {
 /// LED brightness
 #[binrw]
 #[brw(repr = u8)]
-#[derive(Clone, Eq, PartialEq, Debug, EnumString, EnumVariantNames)]
+#[derive(Clone, Eq, PartialEq, Debug, AsRefStr, EnumString, EnumVariantNames)]
 #[strum(serialize_all = "snake_case")]
 pub enum Brightness {
     Off = 0,
@@ -77,9 +90,13 @@ pub trait PayloadType {
 }
 
 /// Payloads
+///
+/// Variants marked as guessed never showed up in a usb capture, their payload
+/// type and layout are assumptions. Commands sending them have to be enabled
+/// with `CherryKeyboard::with_experimental`.
 #[binrw]
 #[br(import(payload_type: u8))]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Payload {
     #[br(pre_assert(payload_type == 0x1))]
     TransactionStart,
@@ -89,6 +106,8 @@ pub enum Payload {
     Unknown3 { unk: u8 },
     #[br(pre_assert(payload_type == 0x5))]
     Unknown5 { unk: u8 },
+    /// Read request, same size as the custom LED table (3 bytes per key), so
+    /// presumably reads back what `SetCustomLED` wrote. Not confirmed yet.
     #[br(pre_assert(payload_type == 0x7))]
     Unknown7 { data_len: u8, data_offset: u16 },
     #[br(pre_assert(payload_type == 0x6))]
@@ -101,6 +120,9 @@ pub enum Payload {
         rainbow: u8,
         color: OwnRGB8,
     },
+    /// Switch the active onboard profile (guessed)
+    #[br(pre_assert(payload_type == 0x8))]
+    ActivateProfile { slot: u8 },
     #[br(pre_assert(payload_type == 0xB))]
     SetCustomLED {
         #[br(temp)]
@@ -111,8 +133,44 @@ pub enum Payload {
         #[br(count = data_len)]
         key_leds_data: Vec<u8>,
     },
+    /// Read request, 1 byte per key. Presumably the key mapping table (see
+    /// `KeyMapping`), which is a guess based on its size.
     #[br(pre_assert(payload_type == 0x1B))]
     Unknown1B { data_len: u8, data_offset: u8 },
+    /// Query polling rate, answered with the rate in the first payload byte (guessed)
+    #[br(pre_assert(payload_type == 0xC))]
+    GetPollingRate,
+    /// Set polling rate (guessed)
+    #[br(pre_assert(payload_type == 0xD))]
+    SetPollingRate { rate: UsbPollingRate },
+    /// Query firmware version, answered with `[major, minor, layout id]` (guessed)
+    #[br(pre_assert(payload_type == 0xE))]
+    GetFirmwareVersion,
+    /// Write the key mapping table, counterpart of `Unknown1B` (guessed)
+    #[br(pre_assert(payload_type == 0x1A))]
+    SetKeyMapping {
+        #[br(temp)]
+        #[bw(calc = key_map_data.len() as u8)]
+        data_len: u8,
+        data_offset: u16,
+        padding: u8,
+        #[br(count = data_len)]
+        key_map_data: Vec<u8>,
+    },
+    /// Upload a macro into `slot`, see `Macro` (guessed)
+    #[br(pre_assert(payload_type == 0x1C))]
+    SetMacro {
+        #[br(temp)]
+        #[bw(calc = macro_data.len() as u8)]
+        data_len: u8,
+        data_offset: u16,
+        slot: u8,
+        #[br(count = data_len)]
+        macro_data: Vec<u8>,
+    },
+    /// Let a key play the macro in `slot` (guessed)
+    #[br(pre_assert(payload_type == 0x1D))]
+    AssignMacro { key_index: u8, slot: u8 },
     Unhandled {
         #[br(parse_with = until_eof)]
         data: Vec<u8>,
@@ -128,8 +186,15 @@ impl PayloadType for Payload {
             Payload::Unknown5 { .. } => 0x5,
             Payload::Unknown7 { .. } => 0x7,
             Payload::SetAnimation { .. } => 0x6,
+            Payload::ActivateProfile { .. } => 0x8,
             Payload::SetCustomLED { .. } => 0xB,
+            Payload::GetPollingRate => 0xC,
+            Payload::SetPollingRate { .. } => 0xD,
+            Payload::GetFirmwareVersion => 0xE,
             Payload::Unknown1B { .. } => 0x1B,
+            Payload::SetKeyMapping { .. } => 0x1A,
+            Payload::SetMacro { .. } => 0x1C,
+            Payload::AssignMacro { .. } => 0x1D,
             _ => {
                 log::error!("Unhandled Payload: {:?}", self);
                 0xFF
@@ -141,7 +206,7 @@ impl PayloadType for Payload {
 /// Common packet structure
 #[binrw]
 #[brw(magic = 4u8)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Packet<T: BinRead<Args = (u8,)> + BinWrite<Args = ()> + PayloadType> {
     // magic, fixed to 0x04, see `br(magic = ...)`
     checksum: u16,
@@ -176,17 +241,117 @@ where
         if calculated == self.checksum {
             Ok(())
         } else {
-            Err(anyhow!(
-                "Invalid checksum, expected: {}, got: {}",
-                calculated,
-                self.checksum
-            ))
+            Err(Error::ChecksumMismatch {
+                expected: calculated,
+                got: self.checksum,
+            })
         }
     }
 }
 
+/// Keyboard state as read by `CherryKeyboard::fetch_device_state`
+///
+/// `Payload::SetAnimation` writes `unknown[0]` bytes at offset `unknown[1..3]` of the
+/// settings block, starting with `unknown[4]`. So the block starts with
+/// `[profile, mode, brightness, speed, pad, rainbow, r, g, b]`, where the
+/// meaning of the first byte is a guess, see `CherryKeyboard::with_profile`.
+/// Fields which could not be decoded are `None`, raw data is kept for the rest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceState {
+    pub mode: Option<LightingMode>,
+    pub brightness: Option<Brightness>,
+    pub speed: Option<Speed>,
+    pub color: Option<OwnRGB8>,
+    pub rainbow: Option<bool>,
+    /// Raw settings block, read via `Payload::Unknown3`
+    pub settings: Vec<u8>,
+    /// Raw data assembled from the `Payload::Unknown7` reads
+    pub unknown7: Vec<u8>,
+    /// Raw data assembled from the `Payload::Unknown1B` reads (presumably key mapping)
+    pub unknown1b: Vec<u8>,
+}
+
+impl DeviceState {
+    pub(crate) const SETTINGS_SIZE: usize = 0x22;
+    /// Custom LED table, 3 bytes per key
+    pub(crate) const UNKNOWN7_SIZE: usize = 0x17A;
+    pub(crate) const UNKNOWN1B_SIZE: usize = 0x7E;
+
+    /// Decode state from the raw data read from the keyboard
+    pub fn decode(settings: Vec<u8>, unknown7: Vec<u8>, unknown1b: Vec<u8>) -> Self {
+        fn field<T: BinRead<Args = ()>>(settings: &[u8], offset: usize) -> Option<T> {
+            let mut cursor = Cursor::new(settings.get(offset..)?);
+            cursor.read_ne().ok()
+        }
+
+        Self {
+            mode: field(&settings, 1),
+            brightness: field(&settings, 2),
+            speed: field(&settings, 3),
+            rainbow: settings.get(5).map(|&rainbow| rainbow != 0),
+            color: field(&settings, 6),
+            settings,
+            unknown7,
+            unknown1b,
+        }
+    }
+}
+
+/// Firmware version as reported by the keyboard
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl FirmwareVersion {
+    /// Parse `UsbIdentity::device_version`, e.g. "1.02"
+    pub fn from_device_version(version: &str) -> Option<Self> {
+        let (major, minor) = version.split_once('.')?;
+
+        Some(Self {
+            major: if major.is_empty() {
+                0
+            } else {
+                major.parse().ok()?
+            },
+            minor: minor.parse().ok()?,
+        })
+    }
+}
+
+impl std::fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{:02}", self.major, self.minor)
+    }
+}
+
+/// Identity of the USB device, taken from its descriptors
+///
+/// String descriptors are optional and may be missing or unreadable.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UsbIdentity {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Device release number (`bcdDevice`), e.g. "1.02"
+    pub device_version: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+/// Device identity as returned by `CherryKeyboard::device_info`
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub usb: UsbIdentity,
+    /// From `bcdDevice`, or the (experimental) firmware query if it was answered
+    pub firmware_version: Option<FirmwareVersion>,
+    /// Raw layout identifier, only reported by the (experimental) firmware query
+    pub layout_id: Option<u8>,
+}
+
 /// Wrapper around custom LED color for all keys
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug, PartialEq)]
 pub struct CustomKeyLeds {
     key_leds: Vec<OwnRGB8>,
 }
@@ -224,7 +389,7 @@ impl BinWrite for CustomKeyLeds {
 }
 
 impl TryFrom<Vec<ProfileKey>> for CustomKeyLeds {
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn try_from(value: Vec<ProfileKey>) -> std::result::Result<Self, Self::Error> {
         let mut custom_keys = Self::new();
@@ -237,24 +402,36 @@ impl TryFrom<Vec<ProfileKey>> for CustomKeyLeds {
     }
 }
 
-impl CustomKeyLeds {
-    /// (64 byte packet - 4 byte packet header - 4 byte payload header)
-    const CHUNK_SIZE: usize = 56;
-    const TOTAL_KEYS: usize = 126;
+/// (64 byte packet - 4 byte packet header - 4 byte payload header)
+const CHUNK_SIZE: usize = 56;
+/// Number of keys / LEDs
+pub(crate) const TOTAL_KEYS: usize = 126;
+
+/// Split `data` into chunked write payloads, `payload` builds one from (data_offset, chunk)
+fn chunk_payloads<F: Fn(u16, Vec<u8>) -> Payload>(data: &[u8], payload: F) -> Vec<Payload> {
+    data.chunks(CHUNK_SIZE)
+        .enumerate()
+        .map(|(index, chunk)| payload((index * CHUNK_SIZE) as u16, chunk.to_vec()))
+        .collect()
+}
 
+impl CustomKeyLeds {
     /// Initialize with inactive colors (000000) for all keys
     pub fn new() -> Self {
         Self {
-            key_leds: (0..CustomKeyLeds::TOTAL_KEYS)
-                .map(|_| OwnRGB8::default())
-                .collect(),
+            key_leds: (0..TOTAL_KEYS).map(|_| OwnRGB8::default()).collect(),
         }
     }
 
+    /// Colors of all keys, indexed by key
+    pub fn key_leds(&self) -> &[OwnRGB8] {
+        &self.key_leds
+    }
+
     /// Initialize from collection of RGB8 values
     pub fn from_leds<C: Into<OwnRGB8>>(key_leds: Vec<C>) -> Result<Self> {
-        if key_leds.len() > CustomKeyLeds::TOTAL_KEYS {
-            return Err(anyhow!("Invalid number of key leds"));
+        if key_leds.len() > TOTAL_KEYS {
+            return Err(Error::InvalidKeyCount(key_leds.len()));
         }
 
         Ok(Self {
@@ -262,34 +439,180 @@ impl CustomKeyLeds {
         })
     }
 
+    /// Initialize from raw LED table, 3 bytes (r, g, b) per key
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::from_leds(
+            data.chunks_exact(3)
+                .map(|rgb| OwnRGB8::new(rgb[0], rgb[1], rgb[2]))
+                .collect(),
+        )
+    }
+
+    /// Get color of key at provided index
+    pub fn get_led(&self, key_index: usize) -> Option<&OwnRGB8> {
+        self.key_leds.get(key_index)
+    }
+
     /// Set color for particular key at provided index
     pub fn set_led<C: Into<OwnRGB8>>(&mut self, key_index: usize, key: C) -> Result<()> {
         if key_index >= self.key_leds.len() {
-            return Err(anyhow!("Key index out of bounds"));
+            return Err(Error::KeyIndexOutOfBounds(key_index));
         }
 
         self.key_leds[key_index] = key.into();
         Ok(())
     }
 
+    /// Set color for a key by its name in `layout`, e.g. "w" or "numpad_enter"
+    pub fn set_key<C: Into<OwnRGB8>>(&mut self, layout: &Layout, name: &str, key: C) -> Result<()> {
+        self.set_led(layout.key_index(name)?, key)
+    }
+
     /// Get array of payloads to be then provided to `send_payload`
     pub fn get_payloads(self) -> Result<Vec<Payload>> {
         let key_data = self.to_vec();
 
-        let result = key_data
-            .chunks(CustomKeyLeds::CHUNK_SIZE)
-            .enumerate()
-            .map(|(index, chunk)| {
-                let data_offset = index * CustomKeyLeds::CHUNK_SIZE;
-
-                Payload::SetCustomLED {
-                    data_offset: data_offset as u16,
-                    padding: 0x00,
-                    key_leds_data: chunk.to_vec(),
-                }
-            })
-            .collect();
+        let result = chunk_payloads(&key_data, |data_offset, chunk| Payload::SetCustomLED {
+            data_offset,
+            padding: 0x00,
+            key_leds_data: chunk,
+        });
 
         Ok(result)
     }
 }
+
+/// Key mapping, HID usage id (keyboard page 0x07) emitted by each key index
+///
+/// Experimental: the table layout is inferred from the size of the `Unknown1B` reads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMapping {
+    usages: Vec<u8>,
+}
+
+impl KeyMapping {
+    /// Initialize from raw mapping table, 1 byte per key
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() > TOTAL_KEYS {
+            return Err(Error::InvalidKeyCount(data.len()));
+        }
+
+        Ok(Self {
+            usages: data.to_vec(),
+        })
+    }
+
+    /// Get HID usage id of key at provided index
+    pub fn get(&self, key_index: usize) -> Option<u8> {
+        self.usages.get(key_index).copied()
+    }
+
+    /// Set HID usage id for particular key at provided index
+    pub fn set(&mut self, key_index: usize, usage: u8) -> Result<()> {
+        if key_index >= self.usages.len() {
+            return Err(Error::KeyIndexOutOfBounds(key_index));
+        }
+
+        self.usages[key_index] = usage;
+        Ok(())
+    }
+
+    /// Get array of payloads to be then provided to `send_payload`
+    pub fn get_payloads(self) -> Result<Vec<Payload>> {
+        let result = chunk_payloads(&self.usages, |data_offset, chunk| Payload::SetKeyMapping {
+            data_offset,
+            padding: 0x00,
+            key_map_data: chunk,
+        });
+
+        Ok(result)
+    }
+}
+
+/// Direction of a macro key event
+#[binrw]
+#[brw(repr = u8)]
+#[derive(Clone, Eq, PartialEq, Debug, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "snake_case")]
+pub enum MacroAction {
+    Release = 0,
+    Press = 1,
+}
+
+/// Single step of a macro
+#[binrw]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacroEvent {
+    pub action: MacroAction,
+    /// HID usage id (keyboard page 0x07)
+    pub usage: u8,
+    /// Delay after this event in milliseconds
+    pub delay_ms: u16,
+}
+
+/// Key sequence stored on the keyboard, see `CherryKeyboard::set_macro`
+///
+/// Experimental: the serialization (event count followed by 4 bytes per event) is a guess.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Macro {
+    events: Vec<MacroEvent>,
+}
+
+impl Macro {
+    /// Maximum number of events per macro
+    pub const MAX_EVENTS: usize = 255;
+
+    /// Initialize from collection of events
+    pub fn new(events: Vec<MacroEvent>) -> Result<Self> {
+        if events.len() > Macro::MAX_EVENTS {
+            return Err(Error::InvalidMacro(format!(
+                "{} events exceed the maximum of {}",
+                events.len(),
+                Macro::MAX_EVENTS
+            )));
+        }
+
+        Ok(Self { events })
+    }
+
+    /// Events of this macro, in order
+    pub fn events(&self) -> &[MacroEvent] {
+        &self.events
+    }
+
+    /// Get array of payloads uploading this macro into `slot`
+    pub fn get_payloads(self, slot: u8) -> Result<Vec<Payload>> {
+        let mut data = vec![self.events.len() as u8];
+        for event in self.events {
+            data.extend(event.to_vec());
+        }
+
+        let result = chunk_payloads(&data, |data_offset, chunk| Payload::SetMacro {
+            data_offset,
+            slot,
+            macro_data: chunk,
+        });
+
+        Ok(result)
+    }
+}
+
+/// Macro bound to a key, as read from a macro file
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacroAssignment {
+    pub key_index: usize,
+    pub sequence: Macro,
+}
+
+/// Lighting setup that can be stored and (re)applied with `CherryKeyboard::apply_lighting`
+#[derive(Clone, Debug, PartialEq)]
+pub enum LightingConfig {
+    Animation {
+        mode: LightingMode,
+        brightness: Brightness,
+        speed: Speed,
+        color: OwnRGB8,
+        rainbow: bool,
+    },
+    CustomColors(CustomKeyLeds),
+}
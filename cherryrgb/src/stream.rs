@@ -0,0 +1,48 @@
+use crate::{models::CustomKeyLeds, transport::Transport, CherryKeyboard, Payload, Result};
+
+/// Streams custom color frames, see `CherryKeyboard::stream_custom_colors`
+///
+/// Only the `SetCustomLED` chunks which changed since the previous frame are
+/// sent, custom mode is entered once when the stream is created. See
+/// `CherryKeyboard::set_custom_colors` for the sequence.
+pub struct CustomColorStream<'a, T: Transport> {
+    keyboard: &'a CherryKeyboard<T>,
+    /// Chunks of the last frame the keyboard acknowledged, `None` to resend everything
+    previous: Option<Vec<Payload>>,
+}
+
+impl<'a, T: Transport> CustomColorStream<'a, T> {
+    pub(crate) fn new(keyboard: &'a CherryKeyboard<T>) -> Self {
+        Self {
+            keyboard,
+            previous: None,
+        }
+    }
+
+    /// Send the chunks of `key_leds` which differ from the previous frame
+    ///
+    /// Returns the number of chunks sent.
+    pub fn send_frame(&mut self, key_leds: CustomKeyLeds) -> Result<usize> {
+        let payloads = key_leds.get_payloads()?;
+        let previous = self.previous.take();
+
+        let changed = payloads
+            .iter()
+            .enumerate()
+            .filter(|(index, payload)| {
+                previous.as_ref().and_then(|p| p.get(*index)) != Some(payload)
+            })
+            .map(|(_, payload)| payload);
+        // On error, `previous` stays cleared and the next frame is sent in full
+        let sent = self.keyboard.send_custom_led_chunks(changed)?;
+        log::trace!("Sent {} of {} custom color chunks", sent, payloads.len());
+
+        self.previous = Some(payloads);
+        Ok(sent)
+    }
+
+    /// Send the next frame in full, e.g. after the keyboard was reset
+    pub fn invalidate(&mut self) {
+        self.previous = None;
+    }
+}
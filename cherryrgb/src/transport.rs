@@ -0,0 +1,212 @@
+use crate::{devices::DeviceDescriptor, models::UsbIdentity, Error, Result};
+use rusb::UsbContext;
+use std::time::Duration;
+
+/// Size of a single HID report exchanged with the keyboard
+pub const REPORT_SIZE: usize = 64;
+
+/// Raw HID report, always padded to `REPORT_SIZE` bytes
+pub type Report = [u8; REPORT_SIZE];
+
+pub(crate) const INTERFACE_NUM: u8 = 1;
+const INTERRUPT_EP: u8 = 0x82;
+
+/// Backend used by `CherryKeyboard` to exchange reports with the device
+pub trait Transport {
+    /// Send a single output report to the keyboard
+    fn send_report(&self, report: &Report, timeout: Duration) -> Result<()>;
+
+    /// Receive a single input report from the keyboard
+    fn recv_report(&self, timeout: Duration) -> Result<Report>;
+
+    /// Identity of the underlying USB device
+    fn identity(&self) -> Result<UsbIdentity> {
+        Ok(UsbIdentity::default())
+    }
+
+    /// Hand the device back, called by `CherryKeyboard::close`
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// libusb based transport, claims the vendor interface of the keyboard
+///
+/// The interface is released and the kernel driver reattached on `Drop`.
+pub struct UsbTransport {
+    device_handle: rusb::DeviceHandle<rusb::Context>,
+    detached_kernel_driver: bool,
+    claimed: bool,
+}
+
+impl UsbTransport {
+    /// Init USB device by verifying number of configurations and claiming appropriate interface
+    pub fn new(vendor_id: u16, product_id: u16) -> Result<Self> {
+        let ctx = rusb::Context::new()?;
+
+        // Open explicitly instead of `open_device_with_vid_pid`, which
+        // hides the reason (e.g. missing permissions) of a failed open
+        let device = ctx
+            .devices()?
+            .iter()
+            .find(|dev| {
+                dev.device_descriptor().is_ok_and(|desc| {
+                    desc.vendor_id() == vendor_id && desc.product_id() == product_id
+                })
+            })
+            .ok_or(Error::NoDeviceFound)?;
+
+        Self::open(device)
+    }
+
+    /// Init the exact USB device described by `descriptor`
+    pub fn from_descriptor(descriptor: &DeviceDescriptor) -> Result<Self> {
+        let ctx = rusb::Context::new()?;
+
+        // Bus number and address are reassigned on replug, so check the ids too
+        let device = ctx
+            .devices()?
+            .iter()
+            .find(|dev| {
+                dev.bus_number() == descriptor.bus_number
+                    && dev.address() == descriptor.address
+                    && dev.device_descriptor().is_ok_and(|desc| {
+                        desc.vendor_id() == descriptor.vendor_id
+                            && desc.product_id() == descriptor.product_id
+                    })
+            })
+            .ok_or(Error::NoDeviceFound)?;
+
+        Self::open(device)
+    }
+
+    /// Open `device`, verify its layout and claim the vendor interface
+    fn open(device: rusb::Device<rusb::Context>) -> Result<Self> {
+        let device_handle = device.open()?;
+
+        let device_desc = device.device_descriptor()?;
+        let config_desc = device.active_config_descriptor()?;
+
+        log::debug!(
+            "* Connected to: Bus {:03} Device {:03} ID {:04x}:{:04x}",
+            device.bus_number(),
+            device.address(),
+            device_desc.vendor_id(),
+            device_desc.product_id()
+        );
+
+        if device_desc.num_configurations() != 1 || config_desc.num_interfaces() != 2 {
+            return Err(Error::UnsupportedLayout {
+                configurations: device_desc.num_configurations(),
+                interfaces: config_desc.num_interfaces(),
+            });
+        }
+
+        let mut transport = Self {
+            device_handle,
+            detached_kernel_driver: false,
+            claimed: false,
+        };
+
+        // Skip kernel driver detachment for non-unix platforms
+        if cfg!(unix) {
+            let kernel_driver_active = transport
+                .device_handle
+                .kernel_driver_active(INTERFACE_NUM)?;
+
+            if kernel_driver_active {
+                transport
+                    .device_handle
+                    .detach_kernel_driver(INTERFACE_NUM)?;
+                transport.detached_kernel_driver = true;
+            }
+        }
+
+        // On failure, dropping `transport` reattaches the kernel driver
+        transport.device_handle.claim_interface(INTERFACE_NUM)?;
+        transport.claimed = true;
+
+        Ok(transport)
+    }
+}
+
+impl Transport for UsbTransport {
+    /// Writes report via control transfer (SET_REPORT)
+    fn send_report(&self, report: &Report, timeout: Duration) -> Result<()> {
+        self.device_handle.write_control(
+            rusb::request_type(
+                rusb::Direction::Out,
+                rusb::RequestType::Class,
+                rusb::Recipient::Interface,
+            ),
+            0x09,   // Request - SET_REPORT
+            0x0204, // Value - ReportId: 4, ReportType: Output
+            0x0001, // Index
+            report, // Data
+            timeout,
+        )?;
+
+        Ok(())
+    }
+
+    /// Reads report from the interrupt endpoint
+    fn recv_report(&self, timeout: Duration) -> Result<Report> {
+        let mut response = [0u8; REPORT_SIZE];
+        self.device_handle.read_interrupt(
+            INTERRUPT_EP,  // Endpoint
+            &mut response, // read buffer
+            timeout,
+        )?;
+
+        Ok(response)
+    }
+
+    /// Read device descriptor and its string descriptors
+    fn identity(&self) -> Result<UsbIdentity> {
+        let desc = self.device_handle.device().device_descriptor()?;
+        let version = desc.device_version();
+
+        Ok(UsbIdentity {
+            vendor_id: desc.vendor_id(),
+            product_id: desc.product_id(),
+            device_version: Some(format!(
+                "{}.{}{}",
+                version.major(),
+                version.minor(),
+                version.sub_minor()
+            )),
+            manufacturer: self
+                .device_handle
+                .read_manufacturer_string_ascii(&desc)
+                .ok(),
+            product: self.device_handle.read_product_string_ascii(&desc).ok(),
+            serial_number: self
+                .device_handle
+                .read_serial_number_string_ascii(&desc)
+                .ok(),
+        })
+    }
+
+    /// Release the interface and reattach the kernel driver, if it was detached by us
+    fn close(&mut self) -> Result<()> {
+        if self.claimed {
+            self.device_handle.release_interface(INTERFACE_NUM)?;
+            self.claimed = false;
+        }
+
+        if self.detached_kernel_driver {
+            self.device_handle.attach_kernel_driver(INTERFACE_NUM)?;
+            self.detached_kernel_driver = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for UsbTransport {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::error!("Failed to hand back keyboard interface: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Daemon owning the keyboard, controlled through a Unix domain socket
+//!
+//! Accepts the JSON requests documented at `cherryrgb::Request`, one per line.
+
+#[cfg(unix)]
+use anyhow::{anyhow, Context, Result};
+#[cfg(unix)]
+use cherryrgb::{CherryKeyboard, DeviceDescriptor, Error};
+#[cfg(unix)]
+use std::{
+    os::unix::net::UnixListener,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+#[cfg(unix)]
+use structopt::StructOpt;
+
+/// Set on SIGINT / SIGTERM, the daemon then closes the keyboard and exits
+#[cfg(unix)]
+static STOP: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+#[derive(StructOpt, Debug)]
+#[structopt(name = "cherryrgbd", about = "Daemon for Cherry RGB Keyboard")]
+struct Opt {
+    /// Enable debug output
+    #[structopt(short, long)]
+    debug: bool,
+
+    #[structopt(long)]
+    product_id: Option<u16>,
+
+    /// Use hidraw instead of libusb, keeps the kernel driver attached
+    #[cfg(target_os = "linux")]
+    #[structopt(long)]
+    hidraw: bool,
+
+    /// Socket to listen on, defaults to $XDG_RUNTIME_DIR/cherryrgbd.sock
+    #[structopt(long, parse(from_os_str))]
+    socket: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+
+    let loglevel = if opt.debug {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+    simple_logger::init_with_level(loglevel).unwrap();
+
+    let socket = opt
+        .socket
+        .clone()
+        .or_else(cherryrgb::default_socket_path)
+        .ok_or_else(|| anyhow!("XDG_RUNTIME_DIR is not set, provide --socket"))?;
+    let listener =
+        cherryrgb::bind(&socket).context(format!("Failed to listen on {}", socket.display()))?;
+    log::info!("Listening on {}", socket.display());
+
+    ctrlc::set_handler(|| STOP.store(true, Ordering::SeqCst))
+        .context("Failed to install signal handler")?;
+
+    let result = serve(&opt, &listener);
+    log::info!("Stopping");
+    if let Err(e) = std::fs::remove_file(&socket) {
+        log::warn!("Failed to remove {}: {}", socket.display(), e);
+    }
+
+    result
+}
+
+/// Find the keyboard to serve, the first one unless `--product-id` is given
+#[cfg(unix)]
+fn find_device(opt: &Opt) -> cherryrgb::Result<DeviceDescriptor> {
+    let devices = cherryrgb::find_devices(opt.product_id)?;
+    if devices.len() > 1 {
+        log::warn!(
+            "More than one keyboard found, using the first one, provide --product-id to pick another"
+        );
+    }
+
+    let device = devices.into_iter().next().ok_or(Error::NoDeviceFound)?;
+    log::info!("Using {}", device);
+    Ok(device)
+}
+
+#[cfg(unix)]
+fn serve(opt: &Opt, listener: &UnixListener) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    if opt.hidraw {
+        let open = || {
+            let device = find_device(opt)?;
+            let transport = cherryrgb::HidrawTransport::from_descriptor(&device)?;
+            Ok(CherryKeyboard::from_transport(transport).with_model(device.model))
+        };
+        return cherryrgb::serve(open, listener, &STOP).context("Failed to serve clients");
+    }
+
+    let open = || CherryKeyboard::open(&find_device(opt)?);
+    cherryrgb::serve(open, listener, &STOP).context("Failed to serve clients")
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("cherryrgbd requires Unix domain sockets");
+    std::process::exit(1);
+}
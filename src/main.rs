@@ -1,12 +1,50 @@
-use std::{convert::TryFrom, io::Read, path::PathBuf};
+use std::{
+    convert::TryFrom,
+    io::Read,
+    path::PathBuf,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context, Result};
 use cherryrgb::{
-    self, read_color_profile, rgb, Brightness, CherryKeyboard, CustomKeyLeds, LightingMode,
-    OwnRGB8, Speed,
+    self, read_color_profile_with_layout, read_macro_file, rgb, Brightness, CherryKeyboard,
+    ColorRamp, CustomKeyLeds, DeviceDescriptor, Effect, GradientSweep, HotplugEvent,
+    HotplugWatcher, KeyBreathing, KeyEventReader, KeyPressCounts, Layout, LightingConfig,
+    LightingMode, OwnRGB8, Plasma, Speed, Transport, UsbPollingRate,
 };
+#[cfg(unix)]
+use cherryrgb::{DaemonClient, Request};
+#[cfg(target_os = "linux")]
+use cherryrgb::{FadingTrail, ReactiveEffect, Ripple, TypingHeatmap};
+#[cfg(target_os = "linux")]
+use std::{sync::mpsc::RecvTimeoutError, time::Instant};
 use structopt::StructOpt;
 use strum::VariantNames;
+use strum_macros::{EnumString, EnumVariantNames};
+
+/// Delay between a keyboard showing up and sending it commands
+const SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// How often commands running until interrupted check for Ctrl+C
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Set on SIGINT / SIGTERM, commands running until interrupted return normally
+/// so the keyboard is closed and state is saved
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Interval at which a heatmap being recorded is written to disk
+#[cfg(target_os = "linux")]
+const HEATMAP_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(StructOpt, Debug)]
+struct ProfileSlotOption {
+    /// Onboard profile slot to write to, defaults to the current one (experimental)
+    #[structopt(long)]
+    profile: Option<u8>,
+}
 
 #[derive(StructOpt, Debug)]
 struct AnimationArgs {
@@ -24,17 +62,311 @@ struct AnimationArgs {
     /// Enable rainbow colors
     #[structopt(short, long)]
     rainbow: bool,
+
+    #[structopt(flatten)]
+    slot: ProfileSlotOption,
+}
+
+impl AnimationArgs {
+    /// Configured color, white if none was given
+    fn color_or_white(&self) -> OwnRGB8 {
+        self.color
+            .clone()
+            .unwrap_or_else(|| rgb::RGB8::new(255, 255, 255).into())
+    }
 }
 
 #[derive(StructOpt, Debug)]
 struct CustomColorOptions {
     colors: Vec<OwnRGB8>,
+
+    #[structopt(flatten)]
+    slot: ProfileSlotOption,
+}
+
+impl CustomColorOptions {
+    /// Assign the colors to the keys, in order
+    fn key_leds(&self) -> Result<CustomKeyLeds> {
+        let mut keys = CustomKeyLeds::new();
+
+        for (index, color) in self.colors.iter().enumerate() {
+            keys.set_led(index, color.clone())?;
+        }
+
+        Ok(keys)
+    }
 }
 
 #[derive(StructOpt, Debug)]
 struct ColorProfileFileOptions {
     #[structopt(parse(from_os_str))]
     file_path: PathBuf,
+
+    #[structopt(flatten)]
+    slot: ProfileSlotOption,
+}
+
+impl ColorProfileFileOptions {
+    /// Read the color profile file
+    fn read(&self) -> Result<String> {
+        let path_str = self
+            .file_path
+            .to_str()
+            .map_or(String::new(), |p| p.to_string());
+
+        let mut f =
+            std::fs::File::open(&self.file_path).context(format!("color profile '{path_str}'"))?;
+        let mut json: String = String::new();
+
+        f.read_to_string(&mut json)?;
+        Ok(json)
+    }
+
+    /// Read and parse the color profile file, resolving key names in `layout`
+    fn key_leds(&self, layout: &Layout) -> Result<CustomKeyLeds> {
+        let colors_from_file = read_color_profile_with_layout(&self.read()?, layout)
+            .context("reading colors from color file")?;
+
+        CustomKeyLeds::try_from(colors_from_file).context("assembling custom key leds")
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum ReapplyCommand {
+    Animation(AnimationArgs),
+    CustomColors(CustomColorOptions),
+    ColorProfileFile(ColorProfileFileOptions),
+}
+
+impl ReapplyCommand {
+    /// Lighting to apply to a keyboard with `layout`
+    fn lighting_config(&self, brightness: &Brightness, layout: &Layout) -> Result<LightingConfig> {
+        Ok(match self {
+            ReapplyCommand::Animation(args) => LightingConfig::Animation {
+                mode: args.mode.clone(),
+                brightness: brightness.clone(),
+                speed: args.speed.clone(),
+                color: args.color_or_white(),
+                rainbow: args.rainbow,
+            },
+            ReapplyCommand::CustomColors(args) => LightingConfig::CustomColors(args.key_leds()?),
+            ReapplyCommand::ColorProfileFile(args) => {
+                LightingConfig::CustomColors(args.key_leds(layout)?)
+            }
+        })
+    }
+
+    fn profile_slot(&self) -> Option<u8> {
+        match self {
+            ReapplyCommand::Animation(args) => args.slot.profile,
+            ReapplyCommand::CustomColors(args) => args.slot.profile,
+            ReapplyCommand::ColorProfileFile(args) => args.slot.profile,
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct WatchOptions {
+    /// Lighting to reapply whenever a keyboard is attached
+    #[structopt(subcommand)]
+    reapply: Option<ReapplyCommand>,
+}
+
+#[derive(StructOpt, Debug)]
+struct BrightnessOptions {
+    #[structopt(possible_values = Brightness::VARIANTS)]
+    level: Brightness,
+}
+
+#[derive(StructOpt, Debug)]
+struct ProfileOptions {
+    /// Onboard profile slot to activate
+    slot: u8,
+}
+
+#[derive(Debug, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "snake_case")]
+enum EffectName {
+    Gradient,
+    Plasma,
+    Breathing,
+}
+
+#[derive(StructOpt, Debug)]
+struct EffectOptions {
+    /// Effect to play
+    #[structopt(possible_values = EffectName::VARIANTS)]
+    name: EffectName,
+
+    /// Colors used by the effect (e.g ff00ff), gradient takes two, breathing one
+    colors: Vec<OwnRGB8>,
+
+    /// Frames per second sent to the keyboard
+    #[structopt(long, default_value = "30")]
+    fps: u32,
+
+    /// Length of one effect cycle in seconds
+    #[structopt(long, default_value = "5")]
+    period: f32,
+
+    /// Stop after this many seconds, runs until interrupted otherwise
+    #[structopt(long)]
+    duration: Option<f32>,
+}
+
+impl EffectOptions {
+    fn effect(&self) -> Result<Box<dyn Effect>> {
+        let period = Duration::try_from_secs_f32(self.period).context("Invalid period")?;
+        let color = |index: usize, default: rgb::RGB8| {
+            self.colors
+                .get(index)
+                .map_or(default, |color| color.clone().into())
+        };
+
+        Ok(match self.name {
+            EffectName::Gradient => Box::new(GradientSweep {
+                from: color(0, rgb::RGB8::new(255, 0, 0)),
+                to: color(1, rgb::RGB8::new(0, 0, 255)),
+                period,
+            }),
+            EffectName::Plasma => Box::new(Plasma { period }),
+            EffectName::Breathing => Box::new(KeyBreathing {
+                color: color(0, rgb::RGB8::new(255, 255, 255)),
+                period,
+            }),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Debug, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "snake_case")]
+enum ReactiveEffectName {
+    Trail,
+    Ripple,
+    Heatmap,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(StructOpt, Debug)]
+struct ReactiveOptions {
+    /// Effect to play
+    #[structopt(possible_values = ReactiveEffectName::VARIANTS)]
+    name: ReactiveEffectName,
+
+    /// Color of pressed keys (e.g ff00ff), not used by heatmap
+    color: Option<OwnRGB8>,
+
+    /// Input device to read key presses from, defaults to the keyboard's /dev/input/event* nodes
+    #[structopt(long, parse(from_os_str))]
+    input: Vec<PathBuf>,
+
+    /// Frames per second sent to the keyboard
+    #[structopt(long, default_value = "30")]
+    fps: u32,
+
+    /// Seconds a pressed key or ripple takes to fade out
+    #[structopt(long, default_value = "1")]
+    fade: f32,
+
+    /// Stop after this many seconds, runs until interrupted otherwise
+    #[structopt(long)]
+    duration: Option<f32>,
+}
+
+#[cfg(target_os = "linux")]
+impl ReactiveOptions {
+    fn effect(&self) -> Result<Box<dyn ReactiveEffect>> {
+        let fade = Duration::try_from_secs_f32(self.fade).context("Invalid fade time")?;
+        let color = self
+            .color
+            .clone()
+            .map_or(rgb::RGB8::new(255, 255, 255), |color| color.into());
+
+        Ok(match self.name {
+            ReactiveEffectName::Trail => Box::new(FadingTrail::new(color, fade)),
+            ReactiveEffectName::Ripple => Box::new(Ripple::new(color, 10.0, fade)),
+            ReactiveEffectName::Heatmap => Box::new(TypingHeatmap::new()),
+        })
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct HeatmapRecordOptions {
+    /// Heatmap file, counts are added to it if it exists
+    #[structopt(parse(from_os_str))]
+    file_path: PathBuf,
+
+    /// Count the presses of a recorded event log (e.g. a copy of /dev/input/event*) instead
+    #[structopt(long, parse(from_os_str))]
+    from_log: Option<PathBuf>,
+
+    /// Key layout (full-size-ansi, full-size-iso, tkl-ansi, tkl-iso), defaults to the keyboard's
+    #[structopt(long)]
+    layout: Option<Layout>,
+
+    /// Input device to read key presses from, defaults to the keyboard's /dev/input/event* nodes
+    #[cfg(target_os = "linux")]
+    #[structopt(long, parse(from_os_str))]
+    input: Vec<PathBuf>,
+
+    /// Stop after this many seconds, records until interrupted otherwise
+    #[cfg(target_os = "linux")]
+    #[structopt(long)]
+    duration: Option<f32>,
+}
+
+#[derive(StructOpt, Debug)]
+struct HeatmapShowOptions {
+    /// Heatmap file written by `heatmap record`
+    #[structopt(parse(from_os_str))]
+    file_path: PathBuf,
+
+    /// Colors from the least to the most pressed key (e.g. 0000ff,ff0000)
+    #[structopt(long, default_value = "0000ff,00ff00,ffff00,ff0000")]
+    ramp: ColorRamp,
+}
+
+impl HeatmapShowOptions {
+    /// Read the heatmap file and grade the keys
+    fn key_leds(&self) -> Result<CustomKeyLeds> {
+        let counts = KeyPressCounts::load(&self.file_path)
+            .context(format!("heatmap file '{}'", self.file_path.display()))?;
+
+        Ok(counts.render(&self.ramp))
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum HeatmapCommand {
+    /// Count key presses into a heatmap file
+    Record(HeatmapRecordOptions),
+    /// Show a heatmap file on the keyboard
+    Show(HeatmapShowOptions),
+}
+
+#[derive(StructOpt, Debug)]
+struct MacroFileOptions {
+    /// JSON file with macro definitions
+    #[structopt(parse(from_os_str))]
+    file_path: PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+struct RemapOptions {
+    /// Key to remap, by name (e.g. caps_lock) or index
+    key: String,
+
+    /// HID usage id the key should emit (e.g. 0xe0 for left ctrl)
+    #[structopt(parse(try_from_str = parse_u8))]
+    usage: u8,
+}
+
+#[derive(StructOpt, Debug)]
+struct PollingRateOptions {
+    /// New polling rate (low: 125Hz, medium: 250Hz, high: 500Hz, full: 1000Hz), omit to query
+    #[structopt(possible_values = UsbPollingRate::VARIANTS)]
+    rate: Option<UsbPollingRate>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -42,6 +374,181 @@ enum CliCommand {
     Animation(AnimationArgs),
     CustomColors(CustomColorOptions),
     ColorProfileFile(ColorProfileFileOptions),
+    /// Remap a single key (experimental)
+    Remap(RemapOptions),
+    /// Upload macros from file and assign them to keys (experimental)
+    Macro(MacroFileOptions),
+    /// Query or set USB polling rate (experimental)
+    PollingRate(PollingRateOptions),
+    /// Activate onboard profile (experimental)
+    Profile(ProfileOptions),
+    /// Print firmware version and USB identity of the keyboard
+    Info,
+    /// Report attached / detached keyboards, optionally reapplying lighting to them
+    Watch(WatchOptions),
+    /// Change brightness of the lighting last set through cherryrgbd
+    Brightness(BrightnessOptions),
+    /// Play a software effect, rendered on the host
+    Effect(EffectOptions),
+    /// Light up keys as they are pressed, reading key events from evdev
+    #[cfg(target_os = "linux")]
+    Reactive(ReactiveOptions),
+    /// Record which keys are pressed most and show them as a heatmap
+    Heatmap(HeatmapCommand),
+}
+
+impl CliCommand {
+    /// Name of the command, if it is experimental and has to be enabled with --experimental
+    fn experimental(&self) -> Option<&'static str> {
+        match self {
+            CliCommand::Remap(_) => Some("remap"),
+            CliCommand::Macro(_) => Some("macro"),
+            CliCommand::PollingRate(_) => Some("polling-rate"),
+            CliCommand::Profile(_) => Some("profile"),
+            _ if self.profile_slot().is_some() => Some("--profile"),
+            _ => None,
+        }
+    }
+
+    /// Name of the command, if it keeps running until interrupted
+    fn long_running(&self) -> Option<&'static str> {
+        match self {
+            CliCommand::Effect(_) => Some("effect"),
+            #[cfg(target_os = "linux")]
+            CliCommand::Reactive(_) => Some("reactive"),
+            CliCommand::Heatmap(HeatmapCommand::Record(_)) => Some("heatmap record"),
+            _ => None,
+        }
+    }
+
+    /// Onboard profile slot the command writes to
+    fn profile_slot(&self) -> Option<u8> {
+        match self {
+            CliCommand::Animation(args) => args.slot.profile,
+            CliCommand::CustomColors(args) => args.slot.profile,
+            CliCommand::ColorProfileFile(args) => args.slot.profile,
+            CliCommand::Watch(args) => args.reapply.as_ref().and_then(|r| r.profile_slot()),
+            _ => None,
+        }
+    }
+
+    /// Requests to send to cherryrgbd, `None` if the daemon does not support the command
+    #[cfg(unix)]
+    fn daemon_requests(&self, brightness: &Brightness) -> Result<Option<Vec<Request>>> {
+        Ok(Some(match self {
+            CliCommand::Animation(args) => vec![Request::SetAnimation {
+                mode: args.mode.clone(),
+                brightness: brightness.clone(),
+                speed: args.speed.clone(),
+                color: args.color_or_white(),
+                rainbow: args.rainbow,
+            }],
+            CliCommand::CustomColors(args) => vec![
+                Request::ResetCustomColors,
+                Request::SetCustomColors(args.key_leds()?),
+            ],
+            // Key names are resolved by the daemon, which knows the model
+            CliCommand::ColorProfileFile(args) => {
+                vec![Request::color_profile(&args.read()?)
+                    .context("reading colors from color file")?]
+            }
+            CliCommand::Brightness(args) => vec![Request::SetBrightness(args.level.clone())],
+            CliCommand::Heatmap(HeatmapCommand::Show(args)) => {
+                vec![Request::SetCustomColors(args.key_leds()?)]
+            }
+            _ => return Ok(None),
+        }))
+    }
+}
+
+/// Parse decimal or 0x prefixed hexadecimal byte
+fn parse_u8(src: &str) -> Result<u8, std::num::ParseIntError> {
+    match src.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => src.parse(),
+    }
+}
+
+/// Keyboard selected via `--device`
+#[derive(Debug, PartialEq)]
+enum DeviceSelector {
+    /// Index in the list of found keyboards, e.g. "index:1"
+    Index(usize),
+    /// Bus number and device address, e.g. "1:7"
+    BusAddress(u8, u8),
+    /// Serial number, e.g. "serial:0123"
+    Serial(String),
+    /// Bare number, a serial number if a keyboard has it and an index otherwise
+    SerialOrIndex(String, usize),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        if let Some(index) = src.strip_prefix("index:") {
+            return Ok(DeviceSelector::Index(index.parse()?));
+        }
+        if let Some(serial) = src.strip_prefix("serial:") {
+            return Ok(DeviceSelector::Serial(serial.to_owned()));
+        }
+        if let Some((bus, address)) = src.split_once(':') {
+            if let (Ok(bus), Ok(address)) = (bus.parse(), address.parse()) {
+                return Ok(DeviceSelector::BusAddress(bus, address));
+            }
+        }
+
+        Ok(match src.parse() {
+            Ok(index) => DeviceSelector::SerialOrIndex(src.to_owned(), index),
+            Err(_) => DeviceSelector::Serial(src.to_owned()),
+        })
+    }
+}
+
+impl std::fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceSelector::Index(index) => write!(f, "index:{}", index),
+            DeviceSelector::BusAddress(bus, address) => write!(f, "{}:{}", bus, address),
+            DeviceSelector::Serial(serial) => write!(f, "serial:{}", serial),
+            DeviceSelector::SerialOrIndex(src, _) => write!(f, "{}", src),
+        }
+    }
+}
+
+impl DeviceSelector {
+    /// Serial number the selector can match, the only property kept when a keyboard is replugged
+    fn serial(&self) -> Option<&str> {
+        match self {
+            DeviceSelector::Serial(serial) | DeviceSelector::SerialOrIndex(serial, _) => {
+                Some(serial)
+            }
+            _ => None,
+        }
+    }
+
+    /// Keyboards out of `devices` matching the selector
+    fn select<'a>(&self, devices: &'a [DeviceDescriptor]) -> Vec<&'a DeviceDescriptor> {
+        let by_serial = |serial: &str| -> Vec<&'a DeviceDescriptor> {
+            devices
+                .iter()
+                .filter(|device| device.serial_number.as_deref() == Some(serial))
+                .collect()
+        };
+
+        match self {
+            DeviceSelector::Index(index) => devices.get(*index).into_iter().collect(),
+            DeviceSelector::BusAddress(bus, address) => devices
+                .iter()
+                .filter(|device| device.bus_number == *bus && device.address == *address)
+                .collect(),
+            DeviceSelector::Serial(serial) => by_serial(serial),
+            DeviceSelector::SerialOrIndex(serial, index) => match by_serial(serial) {
+                matched if matched.is_empty() => devices.get(*index).into_iter().collect(),
+                matched => matched,
+            },
+        }
+    }
 }
 
 #[derive(StructOpt, Debug)]
@@ -54,6 +561,35 @@ struct Opt {
     #[structopt(long)]
     product_id: Option<u16>,
 
+    /// Select keyboard by index, serial number or BUS:ADDRESS if more than one is found,
+    /// prefix with index: or serial: to disambiguate numbers. watch only takes a serial number
+    #[structopt(long, conflicts_with = "all")]
+    device: Option<DeviceSelector>,
+
+    /// Apply the command to all found keyboards
+    #[structopt(long)]
+    all: bool,
+
+    /// Enable experimental commands, which rely on unverified protocol guesses
+    /// and may write to the keyboard's onboard storage
+    #[structopt(long)]
+    experimental: bool,
+
+    /// Use hidraw instead of libusb, keeps the kernel driver attached
+    #[cfg(target_os = "linux")]
+    #[structopt(long)]
+    hidraw: bool,
+
+    /// Socket of cherryrgbd, defaults to $XDG_RUNTIME_DIR/cherryrgbd.sock
+    #[cfg(unix)]
+    #[structopt(long, parse(from_os_str))]
+    socket: Option<PathBuf>,
+
+    /// Access the keyboard directly, even if cherryrgbd is running
+    #[cfg(unix)]
+    #[structopt(long)]
+    no_daemon: bool,
+
     // Subcommand
     #[structopt(subcommand)]
     command: CliCommand,
@@ -66,71 +602,367 @@ struct Opt {
 fn main() -> Result<()> {
     let opt = Opt::from_args();
 
+    let loglevel = if opt.debug {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+    simple_logger::init_with_level(loglevel).unwrap();
+
+    if let Some(command) = opt.command.experimental() {
+        if !opt.experimental {
+            return Err(anyhow!(
+                "'{}' is experimental and not verified against hardware, pass --experimental to use it",
+                command
+            ));
+        }
+    }
+
+    if let CliCommand::Watch(args) = &opt.command {
+        return watch(args, &opt);
+    }
+
+    if let CliCommand::Heatmap(HeatmapCommand::Record(args)) = &opt.command {
+        return record_heatmap(args, &opt);
+    }
+
+    #[cfg(unix)]
+    if send_to_daemon(&opt)? {
+        return Ok(());
+    }
+
+    if let CliCommand::Brightness(_) = &opt.command {
+        return Err(anyhow!(
+            "Changing only the brightness needs a running cherryrgbd, which knows the current \
+             lighting, otherwise pass --brightness with 'animation'"
+        ));
+    }
+
     // Search / init usb keyboard
     let devices =
         cherryrgb::find_devices(opt.product_id).context("Failed to find any cherry keyboard")?;
+    let selected = select_devices(&devices, &opt)?;
 
-    if devices.len() > 1 {
-        for (index, &dev) in devices.iter().enumerate() {
-            println!("{}) VEN_ID={}, PROD_ID={}", index, dev.0, dev.1);
+    if let [device] = selected[..] {
+        return open_and_run(device, &opt);
+    }
+
+    let mut failed = 0;
+    for device in &selected {
+        log::info!("Applying to {}", device);
+        match open_and_run(device, &opt) {
+            Ok(()) => println!("OK: {}", device),
+            Err(e) => {
+                failed += 1;
+                println!("FAILED: {}: {:#}", device, e);
+            }
         }
-        return Err(anyhow!(
-            "More than one keyboard found, please provide --product-id"
-        ));
     }
 
-    let (vendor_id, product_id) = devices.first().unwrap().to_owned();
-    let keyboard =
-        CherryKeyboard::new(vendor_id, product_id).context("Failed to create keyboard")?;
+    if failed > 0 {
+        return Err(anyhow!("{} of {} keyboards failed", failed, selected.len()));
+    }
 
-    let loglevel = if opt.debug {
-        log::Level::Debug
-    } else {
-        log::Level::Info
+    Ok(())
+}
+
+/// Keyboards selected by `--device` / `--all`, fails unless exactly one is selected without `--all`
+fn select_devices<'a>(
+    devices: &'a [DeviceDescriptor],
+    opt: &Opt,
+) -> Result<Vec<&'a DeviceDescriptor>> {
+    if opt.all {
+        if let Some(command) = opt.command.long_running() {
+            return Err(anyhow!(
+                "'{}' keeps running on one keyboard, select it with --device instead of --all",
+                command
+            ));
+        }
+        return Ok(devices.iter().collect());
+    }
+
+    let selected = match &opt.device {
+        Some(selector) => selector.select(devices),
+        None => devices.iter().collect(),
     };
-    simple_logger::init_with_level(loglevel).unwrap();
 
-    /* Fun begins */
-    keyboard
-        .fetch_device_state()
-        .context("Fetching device state failed")?;
+    if selected.is_empty() {
+        print_devices(devices);
+        return Err(match &opt.device {
+            Some(selector) => anyhow!("No keyboard matches --device {}", selector),
+            None => anyhow!("No keyboard found"),
+        });
+    }
 
-    match opt.command {
-        CliCommand::CustomColors(args) => {
-            keyboard.reset_custom_colors()?;
-            let mut keys = CustomKeyLeds::new();
+    if selected.len() > 1 {
+        print_devices(devices);
+        return Err(match &opt.device {
+            Some(selector) => anyhow!(
+                "More than one keyboard matches --device {}, select it by BUS:ADDRESS instead",
+                selector
+            ),
+            None => anyhow!("More than one keyboard found, please provide --device or --all"),
+        });
+    }
+
+    Ok(selected)
+}
+
+/// Set `STOP` on Ctrl+C instead of terminating the process
+fn install_stop_handler() -> Result<()> {
+    ctrlc::set_handler(|| STOP.store(true, Ordering::SeqCst))
+        .context("Failed to install signal handler")
+}
+
+fn print_devices(devices: &[DeviceDescriptor]) {
+    for (index, device) in devices.iter().enumerate() {
+        println!("{}) {}", index, device);
+    }
+}
+
+/// Open the keyboard described by `device` and run the command on it
+fn open_and_run(device: &DeviceDescriptor, opt: &Opt) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    if opt.hidraw {
+        let transport = cherryrgb::HidrawTransport::from_descriptor(device)
+            .context("Failed to open hidraw device")?;
+        let keyboard = CherryKeyboard::from_transport(transport)
+            .with_model(device.model)
+            .with_profile(opt.command.profile_slot())
+            .with_experimental(opt.experimental);
+        run(&keyboard, opt)?;
+        return keyboard.close().context("Failed to close keyboard");
+    }
 
-            for (index, color) in args.colors.into_iter().enumerate() {
-                keys.set_led(index, color)?;
+    let keyboard = match CherryKeyboard::open(device) {
+        Ok(keyboard) => keyboard,
+        Err(e) => {
+            let e = anyhow::Error::new(e).context("Failed to create keyboard");
+            // The daemon has claimed the interface, libusb only reports it as busy
+            #[cfg(unix)]
+            if let Some(socket) = running_daemon(opt) {
+                return Err(e.context(format!(
+                    "cherryrgbd is running on {} and holds the keyboard, stop it to run this command",
+                    socket.display()
+                )));
             }
+            return Err(e);
+        }
+    }
+    .with_profile(opt.command.profile_slot())
+        .with_experimental(opt.experimental);
+
+    run(&keyboard, opt)?;
+    keyboard.close().context("Failed to close keyboard")
+}
+
+/// Socket of cherryrgbd if it is running
+#[cfg(unix)]
+fn running_daemon(opt: &Opt) -> Option<PathBuf> {
+    let socket = opt.socket.clone().or_else(cherryrgb::default_socket_path)?;
+    DaemonClient::connect(&socket).ok().map(|_| socket)
+}
+
+/// Send the command to a running cherryrgbd, returns false if it has to be executed directly
+#[cfg(unix)]
+fn send_to_daemon(opt: &Opt) -> Result<bool> {
+    // Device selection, transport and onboard profiles are not handled by the daemon
+    if opt.no_daemon
+        || opt.product_id.is_some()
+        || opt.device.is_some()
+        || opt.all
+        || opt.command.profile_slot().is_some()
+    {
+        return Ok(false);
+    }
+    #[cfg(target_os = "linux")]
+    if opt.hidraw {
+        return Ok(false);
+    }
+
+    let requests = match opt.command.daemon_requests(&opt.brightness)? {
+        Some(requests) => requests,
+        None => return Ok(false),
+    };
+
+    let socket = match opt.socket.clone().or_else(cherryrgb::default_socket_path) {
+        Some(socket) => socket,
+        None => return Ok(false),
+    };
+    let mut client = match DaemonClient::connect(&socket) {
+        Ok(client) => client,
+        Err(_) => return Ok(false),
+    };
+
+    log::debug!("Sending command to daemon at {}", socket.display());
+    for request in &requests {
+        client
+            .send(request)
+            .context("Daemon failed to execute command")?;
+    }
+
+    Ok(true)
+}
+
+/// Report hotplug events until interrupted, reapplying lighting to attached keyboards
+fn watch(args: &WatchOptions, opt: &Opt) -> Result<()> {
+    // Fail early on an invalid color profile instead of on the first replug. Key
+    // names are resolved per attached model, one of the full size layouts has them all.
+    if let Some(reapply) = &args.reapply {
+        reapply
+            .lighting_config(&opt.brightness, &Layout::FULL_SIZE_ANSI)
+            .or_else(|_| reapply.lighting_config(&opt.brightness, &Layout::FULL_SIZE_ISO))?;
+    }
+
+    // Index and bus address change when a keyboard is plugged in again
+    let serial = match &opt.device {
+        Some(selector) => Some(selector.serial().ok_or_else(|| {
+            anyhow!(
+                "watch selects keyboards by serial number, --device {} changes on replug",
+                selector
+            )
+        })?),
+        None => None,
+    };
 
-            keyboard.set_custom_colors(keys)?;
+    let watcher = HotplugWatcher::new(opt.product_id).context("Failed to start hotplug watcher")?;
+    install_stop_handler()?;
+    log::info!("Watching for keyboards, press Ctrl+C to stop");
+
+    while !STOP.load(Ordering::SeqCst) {
+        match watcher
+            .next_event(Some(STOP_CHECK_INTERVAL))
+            .context("Failed to handle USB events")?
+        {
+            Some(HotplugEvent::Arrived(device)) => {
+                println!("Attached: {}", device);
+                let selected = serial.is_none() || device.serial_number.as_deref() == serial;
+                if args.reapply.is_some() && selected {
+                    thread::sleep(SETTLE_DELAY);
+                    match open_and_run(&device, opt) {
+                        Ok(()) => log::info!("Reapplied lighting to {}", device),
+                        Err(e) => log::error!("Failed to reapply lighting to {}: {:#}", device, e),
+                    }
+                }
+            }
+            Some(HotplugEvent::Left(device)) => println!("Detached: {}", device),
+            None => {}
         }
-        CliCommand::ColorProfileFile(args) => {
-            let path_str = args
-                .file_path
-                .to_str()
-                .map_or(String::new(), |p| p.to_string());
+    }
+
+    Ok(())
+}
+
+/// Count key presses into the heatmap file, saving it periodically
+fn record_heatmap(args: &HeatmapRecordOptions, opt: &Opt) -> Result<()> {
+    let counts = KeyPressCounts::load(&args.file_path)
+        .context(format!("heatmap file '{}'", args.file_path.display()))?;
+
+    if let Some(log_path) = &args.from_log {
+        let file =
+            std::fs::File::open(log_path).context(format!("event log '{}'", log_path.display()))?;
+        let mut counts = counts.with_layout(args.layout.unwrap_or_default());
+        let recorded = counts
+            .record_all(KeyEventReader::new(file))
+            .context("reading event log")?;
+
+        log::info!("Recorded {} key presses", recorded);
+        return counts
+            .save(&args.file_path)
+            .context("Failed to save heatmap");
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let (nodes, layout) = if args.input.is_empty() {
+            let devices = cherryrgb::find_devices(opt.product_id)
+                .context("Failed to find any cherry keyboard")?;
+            let device = select_devices(&devices, opt)?[0];
+            let nodes = cherryrgb::find_input_nodes(device.vendor_id, device.product_id)
+                .context("Failed to find input devices of the keyboard")?;
+            let layout = args
+                .layout
+                .or_else(|| device.model.and_then(Layout::for_model))
+                .unwrap_or_default();
+            (nodes, layout)
+        } else {
+            (args.input.clone(), args.layout.unwrap_or_default())
+        };
+        let mut counts = counts.with_layout(layout);
+        let mut sources = Vec::new();
+        for node in &nodes {
+            log::info!("Reading key events from {}", node.display());
+            let file =
+                std::fs::File::open(node).context(format!("input device '{}'", node.display()))?;
+            sources.push(KeyEventReader::new(file));
+        }
+        let duration = args
+            .duration
+            .map(Duration::try_from_secs_f32)
+            .transpose()
+            .context("Invalid duration")?;
+
+        install_stop_handler()?;
+        let events = cherryrgb::forward_key_events(sources);
+        let start = Instant::now();
+        let mut last_save = start;
+        log::info!("Recording key presses, press Ctrl+C to stop");
+
+        while !STOP.load(Ordering::SeqCst) {
+            let timeout = duration.map_or(STOP_CHECK_INTERVAL, |duration| {
+                duration
+                    .saturating_sub(start.elapsed())
+                    .min(STOP_CHECK_INTERVAL)
+            });
+            match events.recv_timeout(timeout) {
+                Ok(event) => {
+                    counts.record(&event);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
 
-            let mut f = std::fs::File::open(&args.file_path)
-                .context(format!("color profile '{path_str}'"))?;
-            let mut json: String = String::new();
+            if duration.is_some_and(|duration| start.elapsed() >= duration) {
+                break;
+            }
+            if last_save.elapsed() >= HEATMAP_SAVE_INTERVAL {
+                counts
+                    .save(&args.file_path)
+                    .context("Failed to save heatmap")?;
+                last_save = Instant::now();
+            }
+        }
 
-            f.read_to_string(&mut json)?;
+        counts
+            .save(&args.file_path)
+            .context("Failed to save heatmap")
+    }
 
-            let colors_from_file =
-                read_color_profile(&json).context("reading colors from color file")?;
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (counts, opt);
+        Err(anyhow!(
+            "Recording key presses requires Linux, provide a recorded event log with --from-log"
+        ))
+    }
+}
 
-            let keys =
-                CustomKeyLeds::try_from(colors_from_file).context("assembling custom key leds")?;
+fn run<T: Transport>(keyboard: &CherryKeyboard<T>, opt: &Opt) -> Result<()> {
+    /* Fun begins */
+    if let Err(e) = keyboard.fetch_device_state() {
+        log::warn!("Fetching device state failed: {}", e);
+    }
 
-            keyboard.set_custom_colors(keys)?;
+    match &opt.command {
+        CliCommand::CustomColors(args) => {
+            keyboard.reset_custom_colors()?;
+            keyboard.set_custom_colors(args.key_leds()?)?;
+        }
+        CliCommand::ColorProfileFile(args) => {
+            keyboard.set_custom_colors(args.key_leds(&keyboard.layout())?)?;
         }
         CliCommand::Animation(args) => {
-            let color = args
-                .color
-                .or_else(|| Some(rgb::RGB8::new(255, 255, 255).into()))
-                .unwrap();
+            let color = args.color_or_white();
 
             log::info!(
                 "Setting: mode={:?} brightness={:?} speed={:?} color={:?}",
@@ -141,10 +973,256 @@ fn main() -> Result<()> {
             );
 
             keyboard
-                .set_led_animation(args.mode, opt.brightness, args.speed, color, args.rainbow)
+                .set_led_animation(
+                    args.mode.clone(),
+                    opt.brightness.clone(),
+                    args.speed.clone(),
+                    color,
+                    args.rainbow,
+                )
                 .context("Failed to set led animation")?;
         }
+        CliCommand::Remap(args) => {
+            let key_index = match args.key.parse() {
+                Ok(key_index) => key_index,
+                Err(_) => keyboard.layout().key_index(&args.key)?,
+            };
+            let mut mapping = keyboard
+                .get_key_mapping()
+                .context("Failed to read key mapping")?;
+
+            log::info!(
+                "Remapping key {}: usage {:?} -> {:#04x}",
+                key_index,
+                mapping.get(key_index),
+                args.usage
+            );
+
+            mapping.set(key_index, args.usage)?;
+            keyboard
+                .set_key_mapping(mapping)
+                .context("Failed to set key mapping")?;
+        }
+        CliCommand::Watch(args) => {
+            if let Some(reapply) = &args.reapply {
+                keyboard
+                    .apply_lighting(&reapply.lighting_config(&opt.brightness, &keyboard.layout())?)
+                    .context("Failed to reapply lighting")?;
+            }
+        }
+        CliCommand::Info => {
+            let info = keyboard
+                .device_info()
+                .context("Failed to query device info")?;
+            let unknown = || "unknown".to_owned();
+
+            println!(
+                "Model: {}",
+                keyboard
+                    .model()
+                    .map_or_else(unknown, |model| model.name.to_owned())
+            );
+            println!(
+                "USB ID: {:04x}:{:04x}",
+                info.usb.vendor_id, info.usb.product_id
+            );
+            println!(
+                "Manufacturer: {}",
+                info.usb.manufacturer.unwrap_or_else(unknown)
+            );
+            println!("Product: {}", info.usb.product.unwrap_or_else(unknown));
+            println!(
+                "Serial number: {}",
+                info.usb.serial_number.unwrap_or_else(unknown)
+            );
+            println!(
+                "Device version: {}",
+                info.usb.device_version.unwrap_or_else(unknown)
+            );
+            println!(
+                "Firmware version: {}",
+                info.firmware_version
+                    .map_or_else(unknown, |version| version.to_string())
+            );
+            println!(
+                "Layout id: {}",
+                info.layout_id
+                    .map_or_else(unknown, |id| format!("{:#04x}", id))
+            );
+        }
+        CliCommand::Brightness(_) => {
+            unreachable!("Brightness is only changed through the daemon")
+        }
+        CliCommand::Effect(args) => {
+            let mut effect = args.effect()?;
+            let duration = args
+                .duration
+                .map(Duration::try_from_secs_f32)
+                .transpose()
+                .context("Invalid duration")?;
+
+            install_stop_handler()?;
+            log::info!("Playing effect {:?} at {} fps", args.name, args.fps);
+            keyboard
+                .play_effect(
+                    &mut *effect,
+                    opt.brightness.clone(),
+                    args.fps,
+                    duration,
+                    &STOP,
+                )
+                .context("Failed to play effect")?;
+        }
+        #[cfg(target_os = "linux")]
+        CliCommand::Reactive(args) => {
+            let mut effect = args.effect()?;
+            let duration = args
+                .duration
+                .map(Duration::try_from_secs_f32)
+                .transpose()
+                .context("Invalid duration")?;
+
+            let nodes = if args.input.is_empty() {
+                let usb = keyboard.transport().identity()?;
+                cherryrgb::find_input_nodes(usb.vendor_id, usb.product_id)
+                    .context("Failed to find input devices of the keyboard")?
+            } else {
+                args.input.clone()
+            };
+            let mut sources = Vec::new();
+            for node in &nodes {
+                log::info!("Reading key events from {}", node.display());
+                let file = std::fs::File::open(node)
+                    .context(format!("input device '{}'", node.display()))?;
+                sources.push(KeyEventReader::new(file));
+            }
+
+            install_stop_handler()?;
+            log::info!(
+                "Playing reactive effect {:?} at {} fps",
+                args.name,
+                args.fps
+            );
+            keyboard
+                .play_reactive_effect(
+                    &mut *effect,
+                    sources,
+                    opt.brightness.clone(),
+                    args.fps,
+                    duration,
+                    &STOP,
+                )
+                .context("Failed to play effect")?;
+        }
+        CliCommand::Heatmap(HeatmapCommand::Show(args)) => {
+            keyboard.set_custom_colors(args.key_leds()?)?;
+        }
+        CliCommand::Heatmap(HeatmapCommand::Record(_)) => {
+            unreachable!("Recording is handled before opening the keyboard")
+        }
+        CliCommand::Profile(args) => {
+            log::info!("Activating profile {}", args.slot);
+            keyboard
+                .set_active_profile(args.slot)
+                .context("Failed to activate profile")?;
+        }
+        CliCommand::PollingRate(args) => match args.rate.clone() {
+            Some(rate) => {
+                log::info!("Setting: polling rate={:?} ({}Hz)", rate, rate.hz());
+                keyboard
+                    .set_polling_rate(rate)
+                    .context("Failed to set polling rate")?;
+            }
+            None => {
+                let rate = keyboard
+                    .polling_rate()
+                    .context("Failed to query polling rate")?;
+                println!("Polling rate: {:?} ({}Hz)", rate, rate.hz());
+            }
+        },
+        CliCommand::Macro(args) => {
+            let content = std::fs::read_to_string(&args.file_path)
+                .context(format!("macro file '{}'", args.file_path.display()))?;
+            let macros = read_macro_file(&content).context("reading macros from macro file")?;
+
+            for (slot, assignment) in macros.into_iter().enumerate() {
+                let slot = u8::try_from(slot).context("Too many macros in macro file")?;
+
+                log::info!(
+                    "Setting macro {}: key={} events={}",
+                    slot,
+                    assignment.key_index,
+                    assignment.sequence.events().len()
+                );
+
+                keyboard
+                    .set_macro(slot, assignment.sequence)
+                    .context("Failed to upload macro")?;
+                keyboard
+                    .assign_macro(assignment.key_index, slot)
+                    .context("Failed to assign macro")?;
+            }
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(bus_number: u8, address: u8, serial_number: Option<&str>) -> DeviceDescriptor {
+        DeviceDescriptor {
+            vendor_id: cherryrgb::CHERRY_USB_VID,
+            product_id: 0x00dd,
+            bus_number,
+            address,
+            port_numbers: vec![1],
+            serial_number: serial_number.map(str::to_owned),
+            model: None,
+        }
+    }
+
+    #[test]
+    fn parse_device_selector() {
+        let parse = |src: &str| src.parse::<DeviceSelector>().unwrap();
+
+        assert_eq!(parse("index:1"), DeviceSelector::Index(1));
+        assert_eq!(parse("serial:12"), DeviceSelector::Serial("12".to_owned()));
+        assert_eq!(parse("1:7"), DeviceSelector::BusAddress(1, 7));
+        assert_eq!(parse("A1B2"), DeviceSelector::Serial("A1B2".to_owned()));
+        assert_eq!(
+            parse("0123"),
+            DeviceSelector::SerialOrIndex("0123".to_owned(), 123)
+        );
+        assert!("index:first".parse::<DeviceSelector>().is_err());
+
+        for src in ["index:1", "serial:12", "1:7", "0123"] {
+            assert_eq!(parse(src).to_string(), src);
+        }
+        assert_eq!(parse("A1B2").serial(), Some("A1B2"));
+        assert_eq!(parse("0123").serial(), Some("0123"));
+        assert_eq!(parse("1:7").serial(), None);
+    }
+
+    #[test]
+    fn select_device() {
+        let devices = vec![
+            device(1, 7, Some("1")),
+            device(1, 8, Some("0123")),
+            device(2, 3, None),
+        ];
+        let select = |src: &str| src.parse::<DeviceSelector>().unwrap().select(&devices);
+
+        assert_eq!(select("index:1"), vec![&devices[1]]);
+        assert_eq!(select("2:3"), vec![&devices[2]]);
+        assert_eq!(select("serial:1"), vec![&devices[0]]);
+        // Bare numbers match serial numbers first
+        assert_eq!(select("0123"), vec![&devices[1]]);
+        assert_eq!(select("1"), vec![&devices[0]]);
+        assert_eq!(select("2"), vec![&devices[2]]);
+        assert!(select("5").is_empty());
+        assert!(select("serial:5").is_empty());
+    }
+}